@@ -1,705 +1,303 @@
 #[macro_use]
-extern crate serde_json;
-extern crate mediawiki_parser;
-extern crate reqwest;
-extern crate wikibase;
-
-//use config::{Config, File};
-use mediawiki_parser::Element;
-use percent_encoding::percent_decode;
-use serde_json::Value;
-use std::collections::HashMap;
-use std::error::Error;
-use std::fs::File;
-use std::fs::OpenOptions;
-use std::io::prelude::*;
-use std::io::BufReader;
-use std::time::Duration;
-use wikibase::entity_container::*;
-use wikibase::mediawiki::api::{Api, NamespaceID};
-use wikibase::mediawiki::title::Title;
-use wikibase::*;
-
-#[derive(Debug, Clone)]
-pub struct MW {
-    pub api: Api,
-    pub ec: EntityContainer,
-    pub bot_log_file: String,
-    pub verbose: bool,
+extern crate clap;
+
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use commons_statements::error::{Error, Result};
+use commons_statements::jobs::{
+    depicts_german_buildings, depicts_p18_and_free_page_image, JobFile,
+};
+use commons_statements::statement::DuplicatePolicy;
+use commons_statements::MW;
+use tracing_subscriber::EnvFilter;
+
+fn app<'a, 'b>() -> App<'a, 'b> {
+    App::new("commons_statements")
+        .version(crate_version!())
+        .about("Adds structured data statements to files on Wikimedia Commons")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .arg(
+            Arg::with_name("ini")
+                .long("ini")
+                .value_name("FILE")
+                .help("Bot configuration file; COMMONS_STATEMENTS_* variables override it")
+                .default_value("bot.ini")
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("api")
+                .long("api")
+                .value_name("URL")
+                .help("Commons API URL; short for --set api.commons=URL")
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("set")
+                .long("set")
+                .value_name("KEY=VALUE")
+                .help("Overrides a setting, e.g. bot.summary_tag=#test")
+                .multiple(true)
+                .number_of_values(1)
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("dry-run")
+                .long("dry-run")
+                .help("Do not edit; print the payloads that would have been sent")
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("no-batch")
+                .long("no-batch")
+                .help("Send each statement as a separate edit")
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("upgrade-rank")
+                .long("upgrade-rank")
+                .help("Raise existing normal-rank statements to preferred instead of skipping them; overrides on_duplicate of job files")
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("state")
+                .long("state")
+                .value_name("FILE")
+                .help("Run state file for resuming interrupted depicts jobs")
+                .default_value("run_state.jsonl")
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("report")
+                .long("report")
+                .value_name("FILE")
+                .help("Write the end-of-run report as JSON")
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("verbose")
+                .short("v")
+                .long("verbose")
+                .help("More log output: -v for debug, -vv for trace; RUST_LOG overrides")
+                .multiple(true)
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("log-json")
+                .long("log-json")
+                .help("Write log lines as JSON")
+                .global(true),
+        )
+        .subcommand(
+            SubCommand::with_name("depicts-sparql")
+                .about("Adds depicts (P180) to the P18 images of items returned by a SPARQL query")
+                .arg(
+                    Arg::with_name("sparql")
+                        .long("sparql")
+                        .value_name("QUERY")
+                        .help("SPARQL query; first variables need to be ?q and ?image")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("desc")
+                        .long("desc")
+                        .value_name("TEXT")
+                        .help("Description tag for the bot log")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("skip-artwork")
+                        .long("skip-artwork")
+                        .help("Skip files the [exclude] rules match, like those using {{Artwork}}"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("run-jobs")
+                .about("Runs the depicts jobs defined in a TOML job file")
+                .arg(
+                    Arg::with_name("file")
+                        .value_name("FILE")
+                        .help("Job file")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("job")
+                        .long("job")
+                        .value_name("DESCRIPTION")
+                        .help("Only run jobs with this description")
+                        .multiple(true)
+                        .number_of_values(1),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("resume")
+                .about("Continues an interrupted depicts job from the run state file"),
+        )
+        .subcommand(
+            SubCommand::with_name("import-bot-log")
+                .about("Copies the entries of an old free-form bot log into the JSON Lines bot log")
+                .arg(
+                    Arg::with_name("file")
+                        .value_name("FILE")
+                        .help("Old bot log")
+                        .default_value("bot.log"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("depicts-pageimage")
+                .about("Adds depicts (P180) where P18 matches the free page image of a sitelinked article")
+                .arg(
+                    Arg::with_name("sparql")
+                        .long("sparql")
+                        .value_name("PART")
+                        .help("SPARQL fragment constraining ?q, e.g. \"?q wdt:P31 wd:Q5\"")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("server")
+                        .long("server")
+                        .value_name("HOST")
+                        .help("Wiki server of the articles, e.g. de.wikipedia.org")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("german-buildings")
+                .about("Adds depicts (P180) to images of main topics of building categories")
+                .arg(
+                    Arg::with_name("psid")
+                        .long("psid")
+                        .value_name("ID")
+                        .help("PetScan ID returning categories with items")
+                        .default_value("11247873"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("geograph")
+                .about("Processes Geograph files from a PetScan result")
+                .arg(
+                    Arg::with_name("psid")
+                        .long("psid")
+                        .value_name("ID")
+                        .help("PetScan ID returning files")
+                        .default_value("13288476"),
+                ),
+        )
 }
 
-impl MW {
-    pub fn new(api_url: &str) -> Self {
-        let mut ret = Self {
-            api: Api::new_from_builder(api_url, Self::get_builder())
-                .expect("MediaWikiAPI new failed"),
-            ec: EntityContainer::new(),
-            bot_log_file: "bot.log".to_string(),
-            verbose: false,
-        };
-        ret.api.set_edit_delay(Some(500)); // 500 ms delay after each edit
-        ret.ec.allow_special_entity_data(false);
-        ret
-    }
-
-    fn get_builder() -> reqwest::ClientBuilder {
-        reqwest::ClientBuilder::new().timeout(Duration::from_secs(240))
-    }
-
-    pub fn new_from_ini_file(filename: &str, api_url: &str) -> Self {
-        let mut settings = config::Config::default();
-        settings.merge(config::File::with_name(filename)).unwrap();
-        let lgname = settings.get_str("user.user").unwrap();
-        let lgpass = settings.get_str("user.pass").unwrap();
-
-        let mut ret = Self::new(api_url);
-        ret.api.set_edit_delay(Some(500)); // Half a second between edits
-        ret.api.login(lgname, lgpass).unwrap();
-        ret
-    }
-
-    pub fn in_bot_log(&self, parts: Vec<&String>) -> bool {
-        let f = match File::open(self.bot_log_file.to_owned()) {
-            Ok(f) => f,
-            _ => return false,
-        };
-        let parts: Vec<String> = parts.iter().map(|s| format!("\"{}\"", s)).collect(); // Quote parts
-        let f = BufReader::new(f);
-        f.lines()
-            .filter_map(|l| l.ok())
-            .any(|l| parts.iter().all(|p| l.contains(p)))
-        /*
-        let ret = ...
-        if self.verbose && ret {
-            println!("Found a row for {:?}", &parts);
-        }
-        ret
-        */
-    }
-
-    pub fn append_log(&self, line: String) {
-        if self.verbose {
-            println!("{:?}", &line);
-        }
-        let mut file = OpenOptions::new()
-            .write(true)
-            .append(true)
-            .open(self.bot_log_file.to_owned())
-            .unwrap();
-        match writeln!(file, "{}", line) {
-            _ => {} // Meh
-        }
-    }
-
-    pub fn api_query_prop2(
-        &self,
-        key1: &str,
-        value1: &str,
-        key2: &str,
-        value2: &String,
-    ) -> Result<Value, Box<dyn Error>> {
-        let params: HashMap<String, String> =
-            vec![("action", "query"), (key1, value1), (key2, value2.as_str())]
-                .iter()
-                .map(|x| (x.0.to_string(), x.1.to_string()))
-                .collect();
-        self.api.get_query_api_json(&params)
-    }
-
-    pub fn get_page_id(&self, title: &Title) -> Result<NamespaceID, Box<dyn Error>> {
-        let res = self.api_query_prop2(
-            "prop",
-            "pageprops",
-            "titles",
-            &title
-                .full_with_underscores(&self.api)
-                .ok_or(format!("No namespace for title {:?}", &title))?,
-        )?;
-        let pages = res["query"]["pages"]
-            .as_object()
-            .ok_or(format!("get_page_id: No object.pages in JSON: {}", res))?;
-        for (page_id, _page) in pages {
-            return match page_id.parse::<NamespaceID>() {
-                Ok(ret) => {
-                    if ret < 0 {
-                        Err(From::from("Page does not exist"))
-                    } else {
-                        Ok(ret)
-                    }
-                }
-                Err(_) => Err(From::from("Can't parse NamespaceID")),
-            };
-        }
-        Err(From::from(format!(
-            "get_page_id: No page ID in JSON: {}",
-            res
-        )))
-    }
-
-    pub fn load_entity<S: Into<String>>(&mut self, entity_id: S) -> Result<Entity, Box<dyn Error>> {
-        self.ec.load_entity(&self.api, entity_id)
-    }
-
-    pub fn wbcreateclaim(
-        self: &mut Self,
-        entity: &String,
-        snaktype: wikibase::SnakType,
-        valuetype: &str,
-        property: &String,
-        value: &wikibase::Value,
-        summary: Option<String>,
-        baserevid: Option<u64>,
-        rank: Option<&str>,
-    ) -> Result<Value, Box<dyn Error>> {
-        let rank = match rank {
-            Some(s) => s,
-            None => "normal",
-        }
-        .to_string();
-
-        let mut params: HashMap<String, String> = HashMap::new();
-
-        let j = json!({"claims":[{"mainsnak":{"snaktype":snaktype,"property":property,"datavalue":{"value":value,"type":valuetype}},"type":"statement","rank":rank}]});
-        let j = ::serde_json::to_string(&j).expect("MW::wbcreateclaim: json::to_string failed");
-
-        params.insert("action".to_string(), "wbeditentity".to_string());
-        params.insert("id".to_string(), entity.to_string());
-        params.insert("data".to_string(), j);
-        /*
-        params.insert("snaktype".to_string(), snaktype);
-        params.insert("property".to_string(), property.to_string());
-        params.insert("value".to_string(), value);
-        */
-        self.add_summary(&mut params, summary);
-        self.add_baserevid(&mut params, baserevid);
-        self.add_bot_flag(&mut params);
-        self.add_edit_token(&mut params)?;
-
-        if false {
-            Ok(json!(params))
-        } else {
-            let ret = self.api.post_query_api_json_mut(&params);
-            if ret.is_ok() {
-                self.ec.remove_entity(entity.to_owned());
-            }
-            ret
-        }
-    }
-
-    fn add_edit_token(
-        self: &mut Self,
-        params: &mut HashMap<String, String>,
-    ) -> Result<(), Box<dyn Error>> {
-        params.insert("token".to_string(), self.api.get_edit_token()?);
-        Ok(())
-    }
-
-    fn add_bot_flag(&self, params: &mut HashMap<String, String>) {
-        if self.api.user().is_bot() {
-            params.insert("bot".to_string(), "1".to_string());
-        }
-    }
-
-    fn add_baserevid(&self, params: &mut HashMap<String, String>, baserevid: Option<u64>) {
-        match baserevid {
-            Some(baserevid) => {
-                params.insert("baserevid".to_string(), baserevid.to_string());
-            }
-            None => {}
-        }
-    }
-
-    fn add_summary(&self, params: &mut HashMap<String, String>, summary: Option<String>) {
-        match summary {
-            Some(s) => {
-                params.insert("summary".to_string(), s);
-            }
-            None => {}
-        }
-    }
-
-    pub fn get_free_page_image(&self, mw_api: &Api, page: &String) -> Option<String> {
-        mw_api
-            .get_query_api_json(&mw_api.params_into(&vec![
-                ("action", "query"),
-                ("prop", "pageprops"),
-                ("titles", page.as_str()),
-            ]))
-            .ok()?["query"]["pages"]
-            .as_object()?
-            .iter()
-            .filter_map(|(_pageid, pagedata)| pagedata["pageprops"]["page_image_free"].as_str())
-            .map(|s| s.to_string())
-            .nth(0)
-    }
-
-    pub fn page_contains_template(&self, page: &String, template: &str) -> bool {
-        match self.api.get_query_api_json(&self.api.params_into(&vec![
-            ("action", "query"),
-            ("prop", "templates"),
-            ("tltemplates", format!("Template:{}", template).as_str()),
-            ("titles", page.as_str()),
-        ])) {
-            Ok(j) => match j["query"]["pages"].as_object() {
-                Some(pages) => pages
-                    .iter()
-                    .any(|(_pageid, pagedata)| pagedata["templates"].is_array()),
-                None => false,
-            },
-
-            _ => false,
-        }
-    }
+fn psid_arg(matches: &ArgMatches) -> u64 {
+    value_t!(matches, "psid", u64).unwrap_or_else(|e| e.exit())
+}
 
-    // file with File: prefix!
-    pub fn is_artwork(&self, file: &String) -> bool {
-        self.page_contains_template(file, "Artwork")
-    }
+fn run_jobs(commons: &mut MW, matches: &ArgMatches) -> Result<()> {
+    let filename = matches.value_of("file").unwrap();
+    let job_file = JobFile::from_file(filename)?;
+    let only: Vec<&str> = matches
+        .values_of("job")
+        .map(|v| v.collect())
+        .unwrap_or_default();
+    job_file
+        .jobs
+        .iter()
+        .filter(|job| only.is_empty() || only.contains(&job.description.as_str()))
+        .try_for_each(|job| commons.run_depicts_job(job))
+}
 
-    pub fn percent_decode_title(s: String) -> String {
-        percent_decode(s.as_bytes())
-            .decode_utf8()
-            .expect(format!("fix_attribute_value: '{}' is not utf8", s).as_str())
-            .replace(' ', "_")
-            .to_string()
+/// Logs go to stderr; stdout is kept for dry-run payloads
+fn init_logging(matches: &ArgMatches) {
+    let level = match matches.occurrences_of("verbose") {
+        0 => "info",
+        1 => "debug",
+        _ => "trace",
+    };
+    let filter = EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| EnvFilter::new(format!("warn,commons_statements={}", level)));
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr);
+    if matches.is_present("log-json") {
+        builder.json().init();
+    } else {
+        builder.init();
     }
+}
 
-    pub fn add_target_prominent(
-        &mut self,
-        source_item: &String,
-        filename: &String,
-        property: &String,
-    ) -> Result<(), Box<dyn Error>> {
-        let new_value =
-            wikibase::Value::Entity(EntityValue::new(EntityType::Item, source_item.clone()));
-
-        let title = Title::new(&filename, 6);
-        let page_id = match self.get_page_id(&title) {
-            Ok(id) => id,
-            Err(_) => {
-                return Err(From::from(format!(
-                    "Could not get page ID for File:{}",
-                    &filename
-                )))
+/// `--set` pairs, then `--api`
+fn setting_overrides(matches: &ArgMatches) -> Result<Vec<(String, String)>> {
+    let mut overrides = vec![];
+    for pair in matches.values_of("set").into_iter().flatten() {
+        let mut kv = pair.splitn(2, '=');
+        match (kv.next(), kv.next()) {
+            (Some(key), Some(value)) if !key.is_empty() => {
+                overrides.push((key.to_string(), value.to_string()))
             }
-        };
-        let media_id = format!("M{}", page_id);
-        //println!("Media ID for {} is {}", title.pretty(), &media_id);
-
-        // Check if this item already has this statement
-        let has_statement: bool = match self.load_entity(media_id.clone()) {
-            Ok(mi) => mi
-                .claims_with_property(property.clone())
-                .iter()
-                .any(|statement| match statement.main_snak().data_value() {
-                    Some(dv) => *dv.value() == new_value,
-                    None => false,
-                }),
-            Err(_) => false,
-        };
-
-        if has_statement {
-            //println!("Already has a statement for {}", &property);
-            return Ok(());
-        }
-
-        match self.wbcreateclaim(
-            &media_id,
-            SnakType::Value,
-            "wikibase-entityid",
-            &property,
-            &new_value,
-            Some(format!(
-                "Used with P18 on Wikidata [[:d:{}|]] #rust_commons_statement",
-                &source_item
-            )),
-            None,
-            Some("preferred"),
-        ) {
-            Ok(_) => {}
-            Err(e) => eprintln!("Error editing: {:?}", e),
+            _ => return Err(Error::Config(format!("--set {}: Expected KEY=VALUE", pair))),
         }
-        Ok(())
-    }
-
-    // First variables need to be ?q and ?image
-    pub fn depicts_p18_sparql(&mut self, sparql: &str, desc: &str, skip_artwork: bool) {
-        let wikidata =
-            Api::new_from_builder("https://www.wikidata.org/w/api.php", MW::get_builder()).unwrap();
-        let json = wikidata.sparql_query(&sparql).expect("SPARQL query failed");
-
-        let candidates: Vec<(String, String)> = match json["results"]["bindings"].as_array() {
-            Some(b) => b,
-            None => panic!("No bindings in SPARQL results"),
-        }
-        .iter()
-        .filter_map(
-            |b| match (b["q"]["value"].as_str(), b["image"]["value"].as_str()) {
-                (Some(q), Some(i)) => Some((
-                    wikidata.extract_entity_from_uri(q).ok()?,
-                    MW::percent_decode_title(i.split('/').last()?.to_string()),
-                )),
-                _ => return None,
-            },
-        )
-        .filter(|(q, image)| !self.in_bot_log(vec![&q, &image]))
-        .collect();
-
-        candidates.iter().for_each(|(q, image)| {
-            self.append_log(format!("{}: {:?} : \"{}\"", desc, &q, &image));
-            if skip_artwork && self.is_artwork(&format!("File:{}", &image)) {
-                return;
-            }
-            match self.add_target_prominent(&q, &image, &"P180".to_string()) {
-                Ok(_) => {}
-                Err(e) => eprintln!("{} / {}: {:?}", q, image, e),
-            }
-        });
     }
-
-    pub fn geograph(&mut self) {
-        let url = "https://petscan.wmflabs.org/?psid=13288476&format=json"; // ~30:13284323 ; all: 11830495
-        let petscan_result = self
-            .api
-            .query_raw(url, &self.api.no_params(), "GET")
-            .expect("Petscan query failed");
-        let petscan_result: Value =
-            serde_json::from_str(&petscan_result).expect("JSON parsing of PetScan result failed");
-
-        let files = match petscan_result["*"][0]["a"]["*"].as_array() {
-            Some(c) => c,
-            None => panic!("PetScan query failed"),
-        };
-        let mut files: HashMap<String, FileContainer> = files
-            .iter()
-            .filter_map(|f| {
-                let name = f["title"].as_str()?;
-                let id = f["id"].as_u64()?;
-                let fc = FileContainer {
-                    name: name.to_string(),
-                    id: id,
-                };
-                Some((fc.m_id(), fc))
-            })
-            .collect();
-
-        let m_ids = files.iter().map(|(k, _v)| k).cloned().collect();
-        let ec = EntityContainer::new();
-        ec.load_entities(&self.api, &m_ids)
-            .expect("Loading entities failed");
-
-        files
-            .iter_mut()
-            .for_each(|(_, f)| f.process(&self.api).unwrap());
-
-        println!("{:?}", files);
+    if let Some(api_url) = matches.value_of("api") {
+        overrides.push(("api.commons".to_string(), api_url.to_string()));
     }
+    Ok(overrides)
 }
 
-#[derive(Debug, Clone)]
-struct FileContainer {
-    name: String,
-    id: u64,
-}
-
-impl FileContainer {
-    pub fn m_id(&self) -> String {
-        format!("M{}", self.id)
-    }
-
-    pub fn page_title(&self) -> String {
-        format!("File:{}", &self.name)
-    }
-
-    pub fn process(&mut self, api: &Api) -> Result<(), Box<dyn Error>> {
-        let page_title = self.page_title();
-        let params = vec![
-            ("page", page_title.as_str()),
-            ("action", "parse"),
-            ("prop", "wikitext"),
-        ];
-        let j = api.query_api_json(&api.params_into(&params), "GET")?;
-        println!("{:#?}", &j);
-        let wikitext = j["parse"]["wikitext"]["*"]
-            .as_str()
-            .ok_or(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                format!("OH NO"),
-            ))?
-            .to_string();
-
-        //println!("{:?}", wikitext);
-
-        let tree = mediawiki_parser::parse(wikitext.as_str())?;
-        //println!("{:#?}", &tree);
-        let document = match tree {
-            Element::Document(d) => d,
-            _ => {
-                eprintln!("Not a document: {:?}", tree);
-                return Ok(());
-            }
-        };
-        document.content.iter().for_each(|e| {
-            match e {
-                Element::Heading(h) => match h.caption.get(0) {
-                    Some(Element::Template(t)) => {
-                        let template = self.get_template_name(t);
-                        match template.map(|x| x.to_owned().as_str()) {
-                            Some("int:filedesc") => println!("File desc heading"),
-                            _ => {
-                                println!("\nDon't know {:#?}", e);
-                                return;
-                            }
-                        }
-                    }
-                    _ => {
-                        println!("\nDon't know {:#?}", e);
-                        return;
-                    }
-                },
-                _ => {
-                    // Ignore
-                    println!("\nDon't know {:#?}", e);
-                }
-            }
-        });
-
-        Ok(())
+fn run(matches: &ArgMatches) -> Result<()> {
+    let ini = matches.value_of("ini").unwrap();
+    let mut overrides = setting_overrides(matches)?;
+    if matches.subcommand_name() == Some("import-bot-log") {
+        // Otherwise the old entries are in the index already, and none get copied
+        overrides.push(("bot.legacy_log".to_string(), String::new()));
     }
-
-    fn get_template_name(&self, t: &mediawiki_parser::Template) -> Option<String> {
-        match t.name.get(0) {
-            Some(Element::Text(txt)) => Some(txt.text.to_string()),
-            _ => None,
-        }
+    let mut commons = MW::new_from_ini_file(ini, &overrides)?;
+    commons.dry_run = matches.is_present("dry-run");
+    commons.batch_edits = !matches.is_present("no-batch");
+    commons.run_state_file = matches.value_of("state").map(|s| s.to_string());
+    if matches.is_present("upgrade-rank") {
+        commons.duplicate_policy = DuplicatePolicy::UpgradeRank;
     }
-}
-
-#[derive(Debug, Clone)]
-struct CategoryItemImage {
-    pub category: String,
-    pub item: Option<String>,
-    pub image: Option<String>,
-}
-
-fn _depicts_german_buildings() {
-    let mut commons = MW::new_from_ini_file("bot.ini", "https://commons.wikimedia.org/w/api.php");
-    let url = "https://petscan.wmflabs.org/?psid=11247873&format=json"; // TODO FIXME
-    let petscan_result = commons
-        .api
-        .query_raw(url, &commons.api.no_params(), "GET")
-        .expect("Petscan query failed");
-    let petscan_result: Value =
-        serde_json::from_str(&petscan_result).expect("JSON parsing of PetScan result failed");
-
-    let categories = match petscan_result["*"][0]["a"]["*"].as_array() {
-        Some(c) => c,
-        None => panic!("PetScan query failed"),
-    };
-    let mut cii: Vec<CategoryItemImage> = categories
-        .iter()
-        .filter_map(|c| match (c["title"].as_str(), c["q"].as_str()) {
-            (Some(title), Some(q)) => Some(CategoryItemImage {
-                category: title.to_string(),
-                item: Some(q.to_string()),
-                image: None,
-            }),
-            _ => None,
-        })
-        .filter(|c| !commons.is_artwork(&c.image.as_ref().unwrap()))
-        .collect();
 
-    // Load entities
-    commons
-        .ec
-        .load_entities(
-            &commons.api,
-            &cii.iter()
-                .map(|c| c.item.as_ref().unwrap().clone())
-                .collect(),
-        )
-        .expect("Loading of entities failed [1]");
-
-    // Category item => main topic
-    let mut to_load: Vec<String> = vec![];
-    cii.iter_mut().for_each(|c| {
-        let entity = match commons.ec.get_entity(c.item.as_ref().unwrap()) {
-            Some(e) => e,
-            None => {
-                c.item = None;
-                return;
-            }
-        };
-        if !entity.has_target_entity("P31", "Q4167836") {
-            return;
+    match matches.subcommand() {
+        ("depicts-sparql", Some(sub)) => commons.depicts_p18_sparql(
+            sub.value_of("sparql").unwrap(),
+            sub.value_of("desc").unwrap(),
+            sub.is_present("skip-artwork"),
+        ),
+        ("run-jobs", Some(sub)) => run_jobs(&mut commons, sub),
+        ("resume", Some(_)) => commons.resume(),
+        ("import-bot-log", Some(sub)) => {
+            let filename = sub.value_of("file").unwrap();
+            let num = commons.bot_log.import_legacy(filename, true)?;
+            println!("Imported {} new entries from {}", num, filename);
+            Ok(())
         }
-        match entity.values_for_property("P301").iter().nth(0) {
-            Some(target) => {
-                //println!("{} => {:?}", c.item.as_ref().unwrap(), target);
-                match target {
-                    wikibase::Value::Entity(e) => {
-                        c.item = Some(e.id().to_string());
-                        to_load.push(e.id().to_string());
-                    }
-                    _ => c.item = None,
-                }
-            }
-            None => c.item = None,
+        ("depicts-pageimage", Some(sub)) => depicts_p18_and_free_page_image(
+            &mut commons,
+            sub.value_of("sparql").unwrap(),
+            sub.value_of("server").unwrap(),
+        ),
+        ("german-buildings", Some(sub)) => depicts_german_buildings(&mut commons, psid_arg(sub)),
+        ("geograph", Some(sub)) => commons.geograph(psid_arg(sub)),
+        _ => unreachable!(),
+    }?;
+    commons.flush_edits()?;
+
+    if matches.subcommand_name() != Some("import-bot-log") {
+        commons.report.finish();
+        eprint!("{}", commons.report);
+        if let Some(filename) = matches.value_of("report") {
+            commons.report.write_json(filename)?;
         }
-    });
-    cii.retain(|c| c.item.is_some());
-
-    // Load remaining items
-    commons
-        .ec
-        .load_entities(&commons.api, &to_load)
-        .expect("Loading of entities failed [2]");
-
-    // Get images
-    cii.iter_mut().for_each(|c| {
-        c.image = match commons.ec.get_entity(c.item.as_ref().unwrap().to_owned()) {
-            Some(item) => item
-                .values_for_property("P18")
-                .iter()
-                .filter_map(|i| match i {
-                    wikibase::Value::StringValue(s) => Some(s.to_owned()),
-                    _ => None,
-                })
-                .nth(0),
-            None => return,
-        };
-    });
-    cii.retain(|c| c.item.is_some() && c.image.is_some());
-
-    // Remove ones we had already
-    cii.retain(|c| {
-        !commons.in_bot_log(vec![&c.item.as_ref().unwrap(), &c.image.as_ref().unwrap()])
-    });
-
-    // Paranoia
-    cii.retain(|c| match commons.ec.get_entity(c.item.as_ref().unwrap()) {
-        Some(entity) => !entity.has_target_entity("P31", "Q4167836"),
-        None => false,
-    });
-
-    // Add "depicts" to files
-    cii.iter()
-        .for_each(|c| match (c.item.as_ref(), c.image.as_ref()) {
-            (Some(item), Some(image)) => {
-                commons.append_log(format!("Adding \"P180\": \"{}\" to \"{}\"", &item, &image));
-                match commons.add_target_prominent(&item, &image, &"P180".to_string()) {
-                    Ok(_) => {}
-                    Err(e) => eprintln!("{:?} : {:?}", c, e),
-                }
-            }
-            _ => {}
-        });
-}
-
-//________________________________________________________________________________________________________________
-
-#[derive(Debug, Clone)]
-struct ItemArticleImagesPageImage {
-    pub q: String,
-    pub article: String,
-    pub p18: Option<String>,
-    pub pageimage: Option<String>,
-}
-
-fn _depicts_p18_and_free_page_image(sparql_part: &str, server: &str) {
-    let mut commons = MW::new_from_ini_file("bot.ini", "https://commons.wikimedia.org/w/api.php");
-    commons.verbose = true;
-    let local_wiki_api = Api::new_from_builder(
-        format!("https://{}/w/api.php", &server).as_str(),
-        MW::get_builder(),
-    )
-    .unwrap();
-    let sparql = format!("SELECT ?q ?image ?article {{ {} . ?q  wdt:P18 ?image . ?article schema:about ?q ; schema:isPartOf <https://{}/> }}",&sparql_part,&server);
-    let wikidata =
-        Api::new_from_builder("https://www.wikidata.org/w/api.php", MW::get_builder()).unwrap();
-    let json = wikidata.sparql_query(&sparql).expect("SPARQL query failed");
-
-    let iaipi: Vec<ItemArticleImagesPageImage> = match json["results"]["bindings"].as_array() {
-        Some(b) => b,
-        None => panic!("No bindings in SPARQL results"),
     }
-    .iter()
-    .filter_map(|b| {
-        let (q, p18, article) = match (
-            b["q"]["value"].as_str(),
-            b["image"]["value"].as_str(),
-            b["article"]["value"].as_str(),
-        ) {
-            (Some(q), Some(i), Some(a)) => (q, i, a),
-            _ => return None,
-        };
-        Some(ItemArticleImagesPageImage {
-            q: wikidata.extract_entity_from_uri(q).ok()?,
-            p18: Some(MW::percent_decode_title(p18.split('/').last()?.to_string())),
-            article: MW::percent_decode_title(article.split('/').last()?.to_string()),
-            pageimage: None,
-        })
-    })
-    .filter(|i| !commons.in_bot_log(vec![&i.q, &i.p18.as_ref().unwrap()]))
-    .collect();
 
-    iaipi.iter().for_each(
-        |x| match commons.get_free_page_image(&local_wiki_api, &x.article) {
-            Some(pageimage) => {
-                if x.p18 == Some(pageimage.to_owned()) {
-                    if !commons.is_artwork(&format!("File:{}", &pageimage)) {
-                        commons.append_log(format!("{:?} : \"{}\"", &x, &pageimage));
-                        match commons.add_target_prominent(
-                            &x.q,
-                            &x.p18.as_ref().unwrap(),
-                            &"P180".to_string(),
-                        ) {
-                            Ok(_) => {}
-                            Err(e) => eprintln!("{:?} : {:?}", x, e),
-                        }
-                    }
-                }
-            }
-            None => {}
-        },
-    );
+    commons
+        .dry_run_edits
+        .iter()
+        .for_each(|payload| println!("{}", payload));
+    Ok(())
 }
 
 fn main() {
-    //depicts_german_buildings();
-    //depicts_p18_and_free_page_image("?q wdt:P31 wd:Q5 ; wdt:P21 wd:Q6581072", "de.wikipedia.org");
-
-    let mut commons = MW::new_from_ini_file("bot.ini", "https://commons.wikimedia.org/w/api.php");
-    commons.verbose = true;
-    commons.geograph();
-    /*
-    commons.depicts_p18_sparql(
-        "SELECT ?q ?image { ?q wdt:P31 wd:Q16521 ; wdt:P105 wd:Q7432 ; wdt:P18 ?image } LIMIT 500",
-        "species",
-        false,
-    );
-    */
-    /*
-    commons.depicts_p18_sparql(
-        "SELECT ?q ?image { ?q wdt:P1435 wd:Q17297633 ; wdt:P18 ?image }",
-        "Bavarian monuments",
-        true,
-    );
-    */
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    #[test]
-    fn is_artwork() {
-        let commons = MW::new("https://commons.wikimedia.org/w/api.php");
-        assert!(commons.is_artwork(
-            &"File:Lady_Elizabeth_Hamilton_(1753–1797),_Countess_of_Derby.jpg".to_string()
-        ));
-        assert!(!commons.is_artwork(
-            &"File:09797jfBarangays_West_Triangle_Quezon_City_Avenue_Bridgefvf_02.jpg".to_string()
-        ));
+    let matches = app().get_matches();
+    init_logging(&matches);
+    if let Err(e) = run(&matches) {
+        eprintln!("{}", e);
+        std::process::exit(1);
     }
 }
@@ -0,0 +1,368 @@
+//! The bot log: which (item, file, property) pairs were dealt with, and how
+
+use crate::error::{Error, Result};
+use regex::Regex;
+use serde_json::Value;
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::io::prelude::*;
+use std::io::BufReader;
+
+/// What happened when the bot dealt with a candidate
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Outcome {
+    Added {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        revid: Option<u64>,
+    },
+    AlreadyPresent,
+    RankUpgraded {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        revid: Option<u64>,
+    },
+    QualifiersMerged {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        revid: Option<u64>,
+    },
+    /// Left out by an exclusion rule
+    Excluded {
+        /// The rule that matched, e.g. "uses {{Artwork}}"
+        rule: String,
+    },
+    PageMissing,
+    ApiError {
+        /// `Error::class` of the failure, e.g. "network" or "api:protectedpage"
+        #[serde(default, skip_serializing_if = "String::is_empty")]
+        class: String,
+        message: String,
+    },
+    /// Waiting in the edit batch; never written to the log
+    #[serde(skip)]
+    Queued {
+        media_id: String,
+    },
+}
+
+impl Outcome {
+    /// Interprets the JSON returned by an edit
+    pub fn from_edit_result(j: &Value) -> Self {
+        Self::from_edit_result_with(j, |revid| Outcome::Added { revid })
+    }
+
+    /// Interprets the JSON returned by an edit; `success` builds the outcome from the new revision ID
+    pub fn from_edit_result_with<F: FnOnce(Option<u64>) -> Outcome>(j: &Value, success: F) -> Self {
+        match Error::from_api_response(j) {
+            Some(e) => Outcome::from_error(&e),
+            None => success(
+                j["entity"]["lastrevid"]
+                    .as_u64()
+                    .or_else(|| j["pageinfo"]["lastrevid"].as_u64()),
+            ),
+        }
+    }
+
+    /// `PageMissing` for a missing page, so it is counted the same way however
+    /// it was noticed; `ApiError` for anything else
+    pub fn from_error(error: &Error) -> Self {
+        match error {
+            Error::MissingPage(_) => Outcome::PageMissing,
+            _ => Outcome::ApiError {
+                class: error.class(),
+                message: error.to_string(),
+            },
+        }
+    }
+
+    /// Failures are worth another try; everything else is done
+    pub fn is_final(&self) -> bool {
+        !matches!(self, Outcome::PageMissing | Outcome::ApiError { .. })
+    }
+}
+
+/// One (item, file, property) pair the bot has dealt with
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BotLogEntry {
+    pub item: String,
+    pub file: String,
+    pub property: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub job: Option<String>,
+    /// `None` for entries imported from the old log, which was written before each edit
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub outcome: Option<Outcome>,
+}
+
+impl BotLogEntry {
+    pub fn new(item: &str, file: &str, property: &str, job: Option<&str>) -> Self {
+        Self {
+            item: item.to_string(),
+            file: BotLog::normalize_file(file),
+            property: property.to_string(),
+            job: job.map(|s| s.to_string()),
+            outcome: None,
+        }
+    }
+
+    pub fn with_outcome(mut self, outcome: Outcome) -> Self {
+        self.outcome = Some(outcome);
+        self
+    }
+
+    /// Whether this entry marks the pair as done
+    pub fn is_final(&self) -> bool {
+        self.outcome.as_ref().map_or(true, |o| o.is_final())
+    }
+
+    /// Parses a line of the old free-form `bot.log`
+    pub fn from_legacy_line(line: &str) -> Option<Self> {
+        lazy_static! {
+            static ref RE_ADDING: Regex =
+                Regex::new(r#"^Adding "(P\d+)": "(Q\d+)" to "(.+)"$"#).unwrap();
+            static ref RE_PAGE_IMAGE: Regex =
+                Regex::new(r#"^ItemArticleImagesPageImage \{ q: "(Q\d+)", .* \} : "(.+)"$"#)
+                    .unwrap();
+            static ref RE_DESC: Regex = Regex::new(r#"^(.*?): "(Q\d+)" : "(.+)"$"#).unwrap();
+        }
+        if let Some(caps) = RE_ADDING.captures(line) {
+            return Some(Self::new(&caps[2], &caps[3], &caps[1], None));
+        }
+        if let Some(caps) = RE_PAGE_IMAGE.captures(line) {
+            return Some(Self::new(&caps[1], &caps[2], "P180", None));
+        }
+        if let Some(caps) = RE_DESC.captures(line) {
+            return Some(Self::new(&caps[2], &caps[3], "P180", Some(&caps[1])));
+        }
+        None
+    }
+
+    fn key(&self) -> (String, String, String) {
+        (self.item.clone(), self.file.clone(), self.property.clone())
+    }
+}
+
+/// JSON Lines store of `BotLogEntry`, indexed in memory on load
+#[derive(Debug, Clone)]
+pub struct BotLog {
+    filename: String,
+    index: HashSet<(String, String, String)>,
+}
+
+impl BotLog {
+    pub fn open(filename: &str) -> Result<Self> {
+        let mut ret = Self {
+            filename: filename.to_string(),
+            index: HashSet::new(),
+        };
+        let f = match File::open(filename) {
+            Ok(f) => f,
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(ret),
+            Err(e) => return Err(Error::LogIo(e)),
+        };
+        for line in lossy_lines(BufReader::new(f)) {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            // A line torn by a crash should not keep the bot from starting
+            let entry: BotLogEntry = match serde_json::from_str(&line) {
+                Ok(entry) => entry,
+                Err(e) => {
+                    warn!("Skipping unparsable line in {}: {}", filename, e);
+                    continue;
+                }
+            };
+            if entry.is_final() {
+                ret.index.insert(entry.key());
+            }
+        }
+        Ok(ret)
+    }
+
+    pub fn filename(&self) -> &str {
+        &self.filename
+    }
+
+    /// Adds the lines of an old free-form `bot.log` to the index, and to the
+    /// JSON Lines file if `persist` is set. Returns the number of entries that
+    /// were not in the index yet.
+    pub fn import_legacy(&mut self, filename: &str, persist: bool) -> Result<usize> {
+        let f = match File::open(filename) {
+            Ok(f) => f,
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(Error::LogIo(e)),
+        };
+        let mut ret = 0;
+        for line in lossy_lines(BufReader::new(f)) {
+            let entry = match BotLogEntry::from_legacy_line(&line?) {
+                Some(entry) => entry,
+                None => continue,
+            };
+            if self.index.contains(&entry.key()) {
+                continue;
+            }
+            ret += 1;
+            if persist {
+                self.append(entry)?;
+            } else {
+                self.index.insert(entry.key());
+            }
+        }
+        Ok(ret)
+    }
+
+    pub fn contains(&self, item: &str, file: &str, property: &str) -> bool {
+        self.index.contains(&(
+            item.to_string(),
+            Self::normalize_file(file),
+            property.to_string(),
+        ))
+    }
+
+    pub fn append(&mut self, entry: BotLogEntry) -> Result<()> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.filename)?;
+        // One write per line, so concurrent or interrupted writers can't interleave
+        let mut line = serde_json::to_string(&entry)?;
+        line.push('\n');
+        file.write_all(line.as_bytes())?;
+        if entry.is_final() {
+            self.index.insert(entry.key());
+        }
+        Ok(())
+    }
+
+    /// File names without namespace prefix, with underscores
+    pub(crate) fn normalize_file(file: &str) -> String {
+        file.trim_start_matches("File:").replace(' ', "_")
+    }
+}
+
+/// The lines of a file, without line breaks. Invalid UTF-8 is replaced rather
+/// than an error, since a line torn by a crash can end inside a character.
+pub(crate) fn lossy_lines<R: BufRead>(
+    mut reader: R,
+) -> impl Iterator<Item = std::io::Result<String>> {
+    std::iter::from_fn(move || {
+        let mut buf = vec![];
+        match reader.read_until(b'\n', &mut buf) {
+            Ok(0) => None,
+            Ok(_) => {
+                let line = String::from_utf8_lossy(&buf);
+                Some(Ok(line.trim_end_matches(&['\n', '\r'][..]).to_string()))
+            }
+            Err(e) => Some(Err(e)),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bot_log_legacy_lines() {
+        assert_eq!(
+            BotLogEntry::from_legacy_line(r#"species: "Q123" : "Foo_bar.jpg""#),
+            Some(BotLogEntry::new(
+                "Q123",
+                "Foo_bar.jpg",
+                "P180",
+                Some("species")
+            ))
+        );
+        assert_eq!(
+            BotLogEntry::from_legacy_line(r#"Adding "P180": "Q456" to "Some building.jpg""#),
+            Some(BotLogEntry::new("Q456", "Some_building.jpg", "P180", None))
+        );
+        assert_eq!(
+            BotLogEntry::from_legacy_line(
+                r#"ItemArticleImagesPageImage { q: "Q789", article: "Jane_Doe", p18: Some("Jane.jpg"), pageimage: None } : "Jane.jpg""#
+            ),
+            Some(BotLogEntry::new("Q789", "Jane.jpg", "P180", None))
+        );
+        assert_eq!(BotLogEntry::from_legacy_line("garbage"), None);
+    }
+
+    #[test]
+    fn bot_log_exact_keys() {
+        let mut log = BotLog {
+            filename: String::new(),
+            index: HashSet::new(),
+        };
+        log.index
+            .insert(BotLogEntry::new("Q12", "Foo bar.jpg", "P180", None).key());
+        assert!(log.contains("Q12", "Foo_bar.jpg", "P180"));
+        assert!(log.contains("Q12", "File:Foo bar.jpg", "P180"));
+        assert!(!log.contains("Q1", "Foo_bar.jpg", "P180"));
+        assert!(!log.contains("Q12", "Foo.jpg", "P180"));
+        assert!(!log.contains("Q12", "Foo_bar.jpg", "P195"));
+    }
+
+    #[test]
+    fn bot_log_import_legacy() {
+        let legacy = crate::mock_api::temp_file("import_legacy.log");
+        std::fs::write(
+            &legacy,
+            &b"species: \"Q1\" : \"A.jpg\"\nspecies: \"Q2\" : \"B.jpg\"\ngarbage \xe2\x80\n"[..],
+        )
+        .unwrap();
+        let filename = crate::mock_api::temp_file("import_legacy.jsonl");
+        let mut log = BotLog::open(&filename).unwrap();
+        log.append(BotLogEntry::new("Q1", "A.jpg", "P180", None))
+            .unwrap();
+        assert_eq!(log.import_legacy(&legacy, true).unwrap(), 1);
+        assert_eq!(log.import_legacy(&legacy, true).unwrap(), 0);
+
+        // Torn lines, one inside the "–" of "Portrait (1753–1797).jpg"
+        let mut file = OpenOptions::new().append(true).open(&filename).unwrap();
+        file.write_all(b"{\"item\":\"Q4\",\"file\":\"Portrait_(1753\xe2\x80\n")
+            .unwrap();
+        file.write_all(br#"{"item":"Q3","fi"#).unwrap();
+        let log = BotLog::open(&filename).unwrap();
+        assert!(log.contains("Q1", "A.jpg", "P180"));
+        assert!(log.contains("Q2", "B.jpg", "P180"));
+        assert!(!log.contains("Q3", "C.jpg", "P180"));
+        let _ = std::fs::remove_file(&legacy);
+        let _ = std::fs::remove_file(&filename);
+    }
+
+    #[test]
+    fn bot_log_outcomes() {
+        let added = Outcome::from_edit_result(&json!({"success":1,"entity":{"lastrevid":1234}}));
+        assert_eq!(added, Outcome::Added { revid: Some(1234) });
+        assert!(added.is_final());
+
+        let error = Outcome::from_edit_result(
+            &json!({"error":{"code":"failed-save","info":"The save has failed."}}),
+        );
+        assert_eq!(
+            error,
+            Outcome::ApiError {
+                class: "api:failed-save".to_string(),
+                message: "failed-save: The save has failed.".to_string()
+            }
+        );
+        assert!(!BotLogEntry::new("Q1", "A.jpg", "P180", None)
+            .with_outcome(error)
+            .is_final());
+        assert!(BotLogEntry::new("Q1", "A.jpg", "P180", None).is_final());
+        assert_eq!(
+            Outcome::from_error(&Error::MissingPage("File:A.jpg".to_string())),
+            Outcome::PageMissing
+        );
+
+        let line = serde_json::to_string(
+            &BotLogEntry::new("Q1", "A.jpg", "P180", None).with_outcome(Outcome::Excluded {
+                rule: "in category Maps".to_string(),
+            }),
+        )
+        .unwrap();
+        assert_eq!(
+            line,
+            r#"{"item":"Q1","file":"A.jpg","property":"P180","outcome":{"type":"excluded","rule":"in category Maps"}}"#
+        );
+    }
+}
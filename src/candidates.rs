@@ -0,0 +1,103 @@
+//! Candidate records produced by the job sources
+
+use crate::error::{Error, Result};
+use mediawiki_parser::Element;
+use wikibase::mediawiki::api::Api;
+
+/// A file from a PetScan result, by name and page ID
+#[derive(Debug, Clone)]
+pub struct FileContainer {
+    pub name: String,
+    pub id: u64,
+}
+
+impl FileContainer {
+    pub fn m_id(&self) -> String {
+        format!("M{}", self.id)
+    }
+
+    pub fn page_title(&self) -> String {
+        format!("File:{}", &self.name)
+    }
+
+    pub fn process(&mut self, api: &Api) -> Result<()> {
+        let page_title = self.page_title();
+        let params = vec![
+            ("page", page_title.as_str()),
+            ("action", "parse"),
+            ("prop", "wikitext"),
+        ];
+        let j = api.query_api_json(&api.params_into(&params), "GET")?;
+        if let Some(e) = Error::from_api_response(&j) {
+            return Err(e);
+        }
+        trace!(response = ?j, "parse");
+        let wikitext = j["parse"]["wikitext"]["*"]
+            .as_str()
+            .ok_or_else(|| Error::Parse(format!("No wikitext for {}", &page_title)))?
+            .to_string();
+
+        //println!("{:?}", wikitext);
+
+        let tree = mediawiki_parser::parse(wikitext.as_str())
+            .map_err(|e| Error::Parse(format!("{}: {}", &page_title, e)))?;
+        //println!("{:#?}", &tree);
+        let document = match tree {
+            Element::Document(d) => d,
+            _ => {
+                warn!("Not a document: {:?}", tree);
+                return Ok(());
+            }
+        };
+        document.content.iter().for_each(|e| {
+            match e {
+                Element::Heading(h) => match h.caption.get(0) {
+                    Some(Element::Template(t)) => {
+                        let template = self.get_template_name(t);
+                        match template.map(|x| x.to_owned().as_str()) {
+                            Some("int:filedesc") => debug!("File desc heading"),
+                            _ => {
+                                trace!("Don't know {:#?}", e);
+                                return;
+                            }
+                        }
+                    }
+                    _ => {
+                        trace!("Don't know {:#?}", e);
+                        return;
+                    }
+                },
+                _ => {
+                    // Ignore
+                    trace!("Don't know {:#?}", e);
+                }
+            }
+        });
+
+        Ok(())
+    }
+
+    fn get_template_name(&self, t: &mediawiki_parser::Template) -> Option<String> {
+        match t.name.get(0) {
+            Some(Element::Text(txt)) => Some(txt.text.to_string()),
+            _ => None,
+        }
+    }
+}
+
+/// A category, its main topic item and that item's P18 image
+#[derive(Debug, Clone)]
+pub struct CategoryItemImage {
+    pub category: String,
+    pub item: Option<String>,
+    pub image: Option<String>,
+}
+
+/// An item with its P18 image, a sitelinked article and that article's free page image
+#[derive(Debug, Clone)]
+pub struct ItemArticleImagesPageImage {
+    pub q: String,
+    pub article: String,
+    pub p18: Option<String>,
+    pub pageimage: Option<String>,
+}
@@ -0,0 +1,124 @@
+//! How the bot identifies itself: bot password, OAuth 2.0 or OAuth 1.0a
+
+use crate::error::{optional_setting, Error, Result};
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION};
+use wikibase::mediawiki::api::{Api, OAuthParams};
+
+/// Credentials from the ini file; the section present selects the kind
+#[derive(Clone)]
+pub enum Credentials {
+    /// `[user]` with `user` and `pass`, from Special:BotPasswords
+    BotPassword { user: String, pass: String },
+    /// `[oauth2]` with the `access_token` of an owner-only consumer
+    OAuth2 { access_token: String },
+    /// `[oauth1]` with `consumer_key`, `consumer_secret`, `access_token` and `access_secret`
+    OAuth1 {
+        consumer_key: String,
+        consumer_secret: String,
+        access_token: String,
+        access_secret: String,
+    },
+}
+
+impl Credentials {
+    /// `[oauth2]` wins over `[oauth1]`, which wins over `[user]`
+    pub fn from_config(settings: &config::Config) -> Result<Self> {
+        if let Some(access_token) = optional_setting(settings.get_str("oauth2.access_token"))? {
+            return Ok(Credentials::OAuth2 { access_token });
+        }
+        if let Some(consumer_key) = optional_setting(settings.get_str("oauth1.consumer_key"))? {
+            return Ok(Credentials::OAuth1 {
+                consumer_key,
+                consumer_secret: settings.get_str("oauth1.consumer_secret")?,
+                access_token: settings.get_str("oauth1.access_token")?,
+                access_secret: settings.get_str("oauth1.access_secret")?,
+            });
+        }
+        match (
+            optional_setting(settings.get_str("user.user"))?,
+            optional_setting(settings.get_str("user.pass"))?,
+        ) {
+            (Some(user), Some(pass)) => Ok(Credentials::BotPassword { user, pass }),
+            _ => Err(Error::Config(
+                "No credentials; need an [oauth2], [oauth1] or [user] section".to_string(),
+            )),
+        }
+    }
+
+    /// OAuth 2.0 tokens go into every request as a bearer header
+    pub fn client_builder(
+        &self,
+        builder: reqwest::ClientBuilder,
+    ) -> Result<reqwest::ClientBuilder> {
+        match self {
+            Credentials::OAuth2 { access_token } => {
+                let mut headers = HeaderMap::new();
+                let value = HeaderValue::from_str(&format!("Bearer {}", access_token))
+                    .map_err(|e| Error::Config(format!("OAuth 2.0 access token: {}", e)))?;
+                headers.insert(AUTHORIZATION, value);
+                Ok(builder.default_headers(headers))
+            }
+            _ => Ok(builder),
+        }
+    }
+
+    /// Logs in with a bot password, or sets up OAuth 1.0a signing
+    pub fn apply(&self, api: &mut Api) -> Result<()> {
+        match self {
+            Credentials::BotPassword { user, pass } => api
+                .login(user.to_owned(), pass.to_owned())
+                .map_err(|e| Error::Login(e.to_string())),
+            Credentials::OAuth2 { .. } => Ok(()),
+            Credentials::OAuth1 {
+                consumer_key,
+                consumer_secret,
+                access_token,
+                access_secret,
+            } => {
+                api.set_oauth(Some(OAuthParams::new_from_json(&json!({
+                    "g_consumer_key": consumer_key,
+                    "g_consumer_secret": consumer_secret,
+                    "g_token_key": access_token,
+                    "g_token_secret": access_secret,
+                }))));
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(ini: &str) -> config::Config {
+        let mut settings = config::Config::default();
+        settings
+            .merge(config::File::from_str(ini, config::FileFormat::Ini))
+            .unwrap();
+        settings
+    }
+
+    #[test]
+    fn credentials_by_section() {
+        let c = Credentials::from_config(&settings("[user]\nuser=Bot@x\npass=secret\n")).unwrap();
+        assert!(matches!(c, Credentials::BotPassword { ref user, .. } if user == "Bot@x"));
+
+        let c = Credentials::from_config(&settings(
+            "[user]\nuser=Bot@x\npass=secret\n[oauth2]\naccess_token=abc\n",
+        ))
+        .unwrap();
+        assert!(matches!(c, Credentials::OAuth2 { ref access_token } if access_token == "abc"));
+
+        let c = Credentials::from_config(&settings(
+            "[oauth1]\nconsumer_key=ck\nconsumer_secret=cs\naccess_token=at\naccess_secret=as\n",
+        ))
+        .unwrap();
+        assert!(matches!(c, Credentials::OAuth1 { ref consumer_key, .. } if consumer_key == "ck"));
+
+        // Incomplete OAuth 1.0a settings are an error, not a fallback
+        let e = Credentials::from_config(&settings("[oauth1]\nconsumer_key=ck\n"));
+        assert!(matches!(e, Err(Error::Config(_))));
+        assert!(Credentials::from_config(&settings("[throttle]\nburst=2\n")).is_err());
+    }
+}
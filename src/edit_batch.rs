@@ -0,0 +1,128 @@
+//! Collects claims per MediaInfo entity so each entity gets a single edit
+
+use crate::bot_log::BotLogEntry;
+use crate::statement::NewClaim;
+
+/// Claims waiting to be sent to one MediaInfo entity
+#[derive(Debug, Clone, Default)]
+pub struct PendingEdit {
+    pub claims: Vec<NewClaim>,
+    pub summaries: Vec<String>,
+    /// Bot log entries for the candidates behind these claims
+    pub entries: Vec<BotLogEntry>,
+}
+
+impl PendingEdit {
+    /// All distinct summaries, in order
+    pub fn summary(&self) -> String {
+        self.summaries.join("; ")
+    }
+}
+
+/// Accumulates claims per MediaInfo entity, so each entity gets a single edit
+#[derive(Debug, Clone)]
+pub struct EditBatch {
+    /// Maximum number of entities to hold before the batch should be sent
+    pub max_entities: usize,
+    pending: Vec<(String, PendingEdit)>,
+}
+
+impl EditBatch {
+    pub fn new(max_entities: usize) -> Self {
+        Self {
+            max_entities,
+            pending: vec![],
+        }
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.pending.len() >= self.max_entities
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    pub fn contains(&self, media_id: &str) -> bool {
+        self.pending.iter().any(|(id, _)| id == media_id)
+    }
+
+    /// Adds a claim, unless an identical one is already pending for this entity
+    pub fn add_claim(&mut self, media_id: &str, claim: NewClaim, summary: String) {
+        let pending = self.get_or_insert(media_id);
+        let j = claim.to_json();
+        if !pending.claims.iter().any(|c| c.to_json() == j) {
+            pending.claims.push(claim);
+        }
+        if !pending.summaries.contains(&summary) {
+            pending.summaries.push(summary);
+        }
+    }
+
+    pub fn add_entry(&mut self, media_id: &str, entry: BotLogEntry) {
+        self.get_or_insert(media_id).entries.push(entry);
+    }
+
+    /// Removes and returns all pending edits
+    pub fn take(&mut self) -> Vec<(String, PendingEdit)> {
+        std::mem::take(&mut self.pending)
+    }
+
+    fn get_or_insert(&mut self, media_id: &str) -> &mut PendingEdit {
+        let pos = match self.pending.iter().position(|(id, _)| id == media_id) {
+            Some(pos) => pos,
+            None => {
+                self.pending
+                    .push((media_id.to_string(), PendingEdit::default()));
+                self.pending.len() - 1
+            }
+        };
+        &mut self.pending[pos].1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::statement::NewSnak;
+
+    #[test]
+    fn edit_batch_merges_per_entity() {
+        let mut batch = EditBatch::new(2);
+        let summary = "Used with P18 on Wikidata [[:d:Q1|]]".to_string();
+        batch.add_claim(
+            "M1",
+            NewClaim::new(NewSnak::entity("P180", "Q1")),
+            summary.clone(),
+        );
+        batch.add_claim(
+            "M2",
+            NewClaim::new(NewSnak::entity("P180", "Q1")),
+            summary.clone(),
+        );
+        batch.add_claim(
+            "M1",
+            NewClaim::new(NewSnak::entity("P180", "Q2")),
+            "Other".to_string(),
+        );
+        batch.add_claim(
+            "M1",
+            NewClaim::new(NewSnak::entity("P180", "Q2")),
+            "Other".to_string(),
+        );
+        batch.add_entry("M1", BotLogEntry::new("Q2", "A.jpg", "P180", None));
+        assert!(batch.is_full());
+        assert!(batch.contains("M2"));
+
+        let pending = batch.take();
+        assert!(batch.is_empty());
+        assert_eq!(pending.len(), 2);
+        assert_eq!(pending[0].0, "M1");
+        assert_eq!(pending[0].1.claims.len(), 2);
+        assert_eq!(pending[0].1.entries.len(), 1);
+        assert_eq!(
+            pending[0].1.summary(),
+            "Used with P18 on Wikidata [[:d:Q1|]]; Other"
+        );
+    }
+}
@@ -0,0 +1,203 @@
+//! The crate error type, and what a batch run should do about each kind
+
+use serde_json::Value;
+use std::fmt;
+use std::time::Duration;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Missing or invalid settings, e.g. in the ini or job file
+    Config(String),
+    Login(String),
+    /// The request did not get a usable response
+    Network(String),
+    /// A server error or HTTP 429, with the wait its `Retry-After` header asks for
+    Http {
+        status: u16,
+        retry_after: Option<Duration>,
+    },
+    /// The API returned an error; `code` is MediaWiki's `error.code`
+    Api {
+        code: String,
+        info: String,
+    },
+    MissingPage(String),
+    /// Unexpected JSON, SPARQL results or wikitext
+    Parse(String),
+    /// Reading or writing the bot log or run state failed
+    LogIo(std::io::Error),
+}
+
+/// What a batch run does with a candidate that failed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Recovery {
+    /// Transient failure; the candidate can be tried again
+    Retry,
+    /// The candidate is the problem; go on with the next one
+    Skip,
+    /// Nothing else will work either
+    Abort,
+}
+
+/// API error codes that go away by themselves
+const TRANSIENT_API_ERRORS: &[&str] = &["maxlag", "ratelimited", "readonly", "badtoken"];
+
+impl Error {
+    /// The error in an API response, if there is one
+    pub fn from_api_response(j: &Value) -> Option<Self> {
+        let error = j["error"].as_object()?;
+        Some(Error::Api {
+            code: error
+                .get("code")
+                .and_then(|c| c.as_str())
+                .unwrap_or("unknown")
+                .to_string(),
+            info: error
+                .get("info")
+                .and_then(|i| i.as_str())
+                .unwrap_or("")
+                .to_string(),
+        })
+    }
+
+    /// The MediaWiki error code, for API errors
+    pub fn api_code(&self) -> Option<&str> {
+        match self {
+            Error::Api { code, .. } => Some(code),
+            _ => None,
+        }
+    }
+
+    /// Kind of error, for reports; API errors come with their code
+    pub fn class(&self) -> String {
+        match self {
+            Error::Config(_) => "config".to_string(),
+            Error::Login(_) => "login".to_string(),
+            Error::Network(_) => "network".to_string(),
+            Error::Http { status, .. } => format!("http:{}", status),
+            Error::Api { code, .. } => format!("api:{}", code),
+            Error::MissingPage(_) => "missing_page".to_string(),
+            Error::Parse(_) => "parse".to_string(),
+            Error::LogIo(_) => "log_io".to_string(),
+        }
+    }
+
+    pub fn recovery(&self) -> Recovery {
+        match self {
+            Error::Config(_) | Error::Login(_) | Error::LogIo(_) => Recovery::Abort,
+            Error::Network(_) | Error::Http { .. } => Recovery::Retry,
+            Error::Api { code, .. } if TRANSIENT_API_ERRORS.contains(&code.as_str()) => {
+                Recovery::Retry
+            }
+            Error::Api { .. } | Error::MissingPage(_) | Error::Parse(_) => Recovery::Skip,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Config(s) => write!(f, "Configuration error: {}", s),
+            Error::Login(s) => write!(f, "Login failed: {}", s),
+            Error::Network(s) => write!(f, "Network error: {}", s),
+            Error::Http { status, .. } => write!(f, "HTTP status {}", status),
+            Error::Api { code, info } => write!(f, "{}: {}", code, info),
+            Error::MissingPage(s) => write!(f, "Page does not exist: {}", s),
+            Error::Parse(s) => write!(f, "Parse error: {}", s),
+            Error::LogIo(e) => write!(f, "Log file error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::LogIo(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// `None` for settings that are not there; other errors stay errors
+pub(crate) fn optional_setting<T>(
+    setting: std::result::Result<T, config::ConfigError>,
+) -> Result<Option<T>> {
+    match setting {
+        Ok(value) => Ok(Some(value)),
+        Err(config::ConfigError::NotFound(_)) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Errors from the mediawiki and wikibase crates are request failures
+impl From<Box<dyn std::error::Error>> for Error {
+    fn from(e: Box<dyn std::error::Error>) -> Self {
+        Error::Network(e.to_string())
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Self {
+        Error::Network(e.to_string())
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Parse(e.to_string())
+    }
+}
+
+impl From<config::ConfigError> for Error {
+    fn from(e: config::ConfigError) -> Self {
+        Error::Config(e.to_string())
+    }
+}
+
+impl From<toml::de::Error> for Error {
+    fn from(e: toml::de::Error) -> Self {
+        Error::Config(e.to_string())
+    }
+}
+
+/// The only files this crate writes are the bot log and the run state
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::LogIo(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recovery_by_kind() {
+        let maxlag =
+            Error::from_api_response(&json!({"error":{"code":"maxlag","info":"Waiting"}})).unwrap();
+        assert_eq!(maxlag.api_code(), Some("maxlag"));
+        assert_eq!(maxlag.recovery(), Recovery::Retry);
+        assert_eq!(maxlag.class(), "api:maxlag");
+        let protected =
+            Error::from_api_response(&json!({"error":{"code":"protectedpage"}})).unwrap();
+        assert_eq!(protected.to_string(), "protectedpage: ");
+        assert_eq!(protected.recovery(), Recovery::Skip);
+        assert!(Error::from_api_response(&json!({"success":1})).is_none());
+        assert_eq!(
+            Error::MissingPage("File:X.jpg".to_string()).recovery(),
+            Recovery::Skip
+        );
+        assert_eq!(
+            Error::Login("WrongPass".to_string()).recovery(),
+            Recovery::Abort
+        );
+        let unavailable = Error::Http {
+            status: 503,
+            retry_after: None,
+        };
+        assert_eq!(unavailable.class(), "http:503");
+        assert_eq!(unavailable.recovery(), Recovery::Retry);
+    }
+}
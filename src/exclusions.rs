@@ -0,0 +1,100 @@
+//! Rules for files that should not get depicts statements, like artworks
+
+use std::fmt;
+
+/// The `[exclude]` section. In the ini file and environment variables, lists
+/// are comma-separated.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct ExclusionConfig {
+    /// Templates, without namespace prefix; redirects to them count, too
+    #[serde(deserialize_with = "list")]
+    pub templates: Vec<String>,
+    /// Categories, without namespace prefix
+    #[serde(deserialize_with = "list")]
+    pub categories: Vec<String>,
+    /// Properties of the MediaInfo entity, e.g. P6243 (digital representation of)
+    #[serde(deserialize_with = "list")]
+    pub properties: Vec<String>,
+}
+
+impl Default for ExclusionConfig {
+    fn default() -> Self {
+        Self {
+            templates: vec!["Artwork".to_string()],
+            categories: vec![],
+            properties: vec!["P6243".to_string()],
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StringOrList {
+    String(String),
+    List(Vec<String>),
+}
+
+/// A list, or a string with comma-separated items
+fn list<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let items = match <StringOrList as serde::Deserialize>::deserialize(deserializer)? {
+        StringOrList::String(s) => s.split(',').map(|item| item.to_string()).collect(),
+        StringOrList::List(list) => list,
+    };
+    Ok(items
+        .iter()
+        .map(|item| item.trim().replace('_', " "))
+        .filter(|item| !item.is_empty())
+        .collect())
+}
+
+/// Why a file is left out
+#[derive(Debug, Clone, PartialEq)]
+pub enum Exclusion {
+    /// Uses this template, or a redirect to it
+    Template(String),
+    Category(String),
+    /// The MediaInfo entity has a statement for this property
+    Property(String),
+}
+
+impl fmt::Display for Exclusion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Exclusion::Template(name) => write!(f, "uses {{{{{}}}}}", name),
+            Exclusion::Category(name) => write!(f, "in category {}", name),
+            Exclusion::Property(property) => write!(f, "has a {} statement", property),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exclusion_config() {
+        let mut config = config::Config::default();
+        config
+            .merge(config::File::from_str(
+                "[exclude]\ntemplates = Artwork, Art_Photo,Book\ncategories = Maps\n",
+                config::FileFormat::Ini,
+            ))
+            .unwrap();
+        let exclude: ExclusionConfig = config.get("exclude").unwrap();
+        assert_eq!(exclude.templates, vec!["Artwork", "Art Photo", "Book"]);
+        assert_eq!(exclude.categories, vec!["Maps"]);
+        assert_eq!(exclude.properties, vec!["P6243"]);
+
+        let exclude: ExclusionConfig = serde_json::from_value(json!({"properties": []})).unwrap();
+        assert!(exclude.properties.is_empty());
+        assert_eq!(exclude.templates, vec!["Artwork"]);
+        assert_eq!(
+            Exclusion::Template("Artwork".to_string()).to_string(),
+            "uses {{Artwork}}"
+        );
+    }
+}
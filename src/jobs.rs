@@ -0,0 +1,684 @@
+//! Depicts jobs: SPARQL, PetScan and page image driven batches
+
+use crate::bot_log::BotLogEntry;
+use crate::candidates::{CategoryItemImage, FileContainer, ItemArticleImagesPageImage};
+use crate::error::{Error, Recovery, Result};
+use crate::mw::MW;
+use crate::run_state::RunState;
+use crate::statement::{DuplicatePolicy, Rank};
+use serde_json::Value;
+use std::collections::HashMap;
+use wikibase::entity_container::EntityContainer;
+
+/// A depicts batch as described in a job file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DepictsJob {
+    /// SPARQL query; first variables need to be ?q and ?image
+    pub query: String,
+    /// Tag for the bot log
+    pub description: String,
+    #[serde(default = "DepictsJob::default_property")]
+    pub property: String,
+    /// "normal", "preferred" or "deprecated"
+    #[serde(default = "DepictsJob::default_rank")]
+    pub rank: Rank,
+    /// Leave out files the `[exclude]` rules match, like artworks
+    #[serde(default)]
+    pub skip_artwork: bool,
+    /// Edit summary; `{item}`, `{file}`, `{property}` and `{description}` are replaced
+    #[serde(default = "DepictsJob::default_summary")]
+    pub summary: String,
+    /// Maximum number of candidates to process in one run
+    #[serde(default)]
+    pub limit: Option<usize>,
+    /// What to do if a file already has the statement, e.g. "upgrade_rank"
+    #[serde(default)]
+    pub on_duplicate: DuplicatePolicy,
+}
+
+impl DepictsJob {
+    pub fn new(query: &str, description: &str) -> Self {
+        Self {
+            query: query.to_string(),
+            description: description.to_string(),
+            property: Self::default_property(),
+            rank: Self::default_rank(),
+            skip_artwork: false,
+            summary: Self::default_summary(),
+            limit: None,
+            on_duplicate: DuplicatePolicy::IgnoreRank,
+        }
+    }
+
+    fn default_property() -> String {
+        "P180".to_string()
+    }
+
+    fn default_rank() -> Rank {
+        Rank::Preferred
+    }
+
+    fn default_summary() -> String {
+        "Used with P18 on Wikidata [[:d:{item}|]]".to_string()
+    }
+
+    pub fn summary_for(&self, item: &str, file: &str) -> String {
+        self.summary
+            .replace("{item}", item)
+            .replace("{file}", file)
+            .replace("{property}", &self.property)
+            .replace("{description}", &self.description)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct JobFile {
+    #[serde(rename = "job", default)]
+    pub jobs: Vec<DepictsJob>,
+}
+
+impl JobFile {
+    pub fn from_file(filename: &str) -> Result<Self> {
+        let contents = std::fs::read_to_string(filename)
+            .map_err(|e| Error::Config(format!("{}: {}", filename, e)))?;
+        Self::from_toml(&contents)
+    }
+
+    pub fn from_toml(contents: &str) -> Result<Self> {
+        Ok(toml::from_str(contents)?)
+    }
+}
+
+impl MW {
+    // First variables need to be ?q and ?image
+    pub fn depicts_p18_sparql(
+        &mut self,
+        sparql: &str,
+        desc: &str,
+        skip_artwork: bool,
+    ) -> Result<()> {
+        let mut job = DepictsJob::new(sparql, desc);
+        job.skip_artwork = skip_artwork;
+        job.on_duplicate = self.duplicate_policy;
+        self.run_depicts_job(&job)
+    }
+
+    /// Fails only if the whole run needs to stop; failed candidates are logged.
+    /// Progress goes to `run_state_file`, if set.
+    pub fn run_depicts_job(&mut self, job: &DepictsJob) -> Result<()> {
+        let candidates = self.depicts_candidates(job)?;
+        if let (Some(filename), false) = (&self.run_state_file, self.dry_run) {
+            self.run_state = Some(RunState::create(filename, job, &candidates)?);
+        }
+        let candidates = candidates
+            .into_iter()
+            .enumerate()
+            .map(|(index, (q, image))| (index, q, image))
+            .collect();
+        self.process_depicts_candidates(job, candidates)
+    }
+
+    /// Continues the run in `run_state_file` with the candidates that have no
+    /// outcome yet, without querying SPARQL again. A dry run leaves the run
+    /// state as it is.
+    pub fn resume(&mut self) -> Result<()> {
+        let filename = self
+            .run_state_file
+            .clone()
+            .ok_or_else(|| Error::Config("No run state file".to_string()))?;
+        let state = RunState::load(&filename)?;
+        if state.finished {
+            return Ok(());
+        }
+        let job = state.job.clone();
+        let candidates: Vec<(usize, String, String)> = state
+            .pending()
+            .into_iter()
+            .map(|index| {
+                let c = &state.candidates[index];
+                (index, c.item.to_owned(), c.file.to_owned())
+            })
+            .collect();
+        if !self.dry_run {
+            self.run_state = Some(state);
+        }
+        self.report.add_candidates(candidates.len(), 0);
+        self.process_depicts_candidates(&job, candidates)
+    }
+
+    /// (item, file) pairs for a job that are not in the bot log yet
+    fn depicts_candidates(&mut self, job: &DepictsJob) -> Result<Vec<(String, String)>> {
+        let wikidata = self.foreign_api(&self.settings.api.wikidata)?;
+        let json = self.sparql_query(&wikidata, &job.query)?;
+
+        let found: Vec<(String, String)> = sparql_bindings(&json)?
+            .iter()
+            .filter_map(
+                |b| match (b["q"]["value"].as_str(), b["image"]["value"].as_str()) {
+                    (Some(q), Some(i)) => Some((
+                        wikidata.extract_entity_from_uri(q).ok()?,
+                        MW::percent_decode_title(i.split('/').last()?.to_string()),
+                    )),
+                    _ => return None,
+                },
+            )
+            .collect();
+        let mut candidates: Vec<(String, String)> = found
+            .iter()
+            .filter(|(q, image)| !self.in_bot_log(q, image, &job.property))
+            .take(job.limit.unwrap_or(usize::MAX))
+            .cloned()
+            .collect();
+        let in_bot_log = found
+            .iter()
+            .filter(|(q, image)| self.in_bot_log(q, image, &job.property))
+            .count();
+        self.report.add_candidates(found.len(), in_bot_log);
+        // Keep candidates for the same file together, so their claims end up in one edit
+        candidates.sort_by(|a, b| a.1.cmp(&b.1));
+        Ok(candidates)
+    }
+
+    /// Works through (index in the run state, item, file) candidates
+    fn process_depicts_candidates(
+        &mut self,
+        job: &DepictsJob,
+        candidates: Vec<(usize, String, String)>,
+    ) -> Result<()> {
+        // `--upgrade-rank` wins over the policy of a job file or resumed run
+        let on_duplicate = match self.duplicate_policy {
+            DuplicatePolicy::UpgradeRank => DuplicatePolicy::UpgradeRank,
+            _ => job.on_duplicate,
+        };
+        let files: Vec<String> = candidates.iter().map(|c| c.2.to_owned()).collect();
+        self.prefetch_files(&files)?;
+        if job.skip_artwork {
+            self.prefetch_exclusions(&files)?;
+        }
+        for (index, q, image) in candidates.iter() {
+            if let Some(state) = self.run_state.as_mut() {
+                state.set_position(*index)?;
+            }
+            let span = info_span!("candidate", item = %q, file = %image);
+            let _enter = span.enter();
+            // Resumed runs may have logged a candidate without recording it in the run state
+            if self.in_bot_log(q, image, &job.property) {
+                continue;
+            }
+            let entry = BotLogEntry::new(q, image, &job.property, Some(&job.description));
+            let excluded = if job.skip_artwork {
+                self.excluded(image)
+            } else {
+                Ok(None)
+            };
+            let result = excluded.and_then(|excluded| {
+                if let Some(outcome) = excluded {
+                    return Ok(outcome);
+                }
+                self.add_target(
+                    q,
+                    image,
+                    &job.property,
+                    job.rank,
+                    job.summary_for(q, image),
+                    on_duplicate,
+                )
+            });
+            self.log_outcome(entry, result)?;
+        }
+        self.flush_edits()?;
+        if let Some(mut state) = self.run_state.take() {
+            state.finish()?;
+        }
+        Ok(())
+    }
+
+    pub fn geograph(&mut self, psid: u64) -> Result<()> {
+        let petscan_result = petscan(self, psid)?;
+        let files = petscan_pages(&petscan_result)?;
+        let mut files: HashMap<String, FileContainer> = files
+            .iter()
+            .filter_map(|f| {
+                let name = f["title"].as_str()?;
+                let id = f["id"].as_u64()?;
+                let fc = FileContainer {
+                    name: name.to_string(),
+                    id: id,
+                };
+                Some((fc.m_id(), fc))
+            })
+            .collect();
+
+        let m_ids = files.iter().map(|(k, _v)| k).cloned().collect();
+        let ec = EntityContainer::new();
+        ec.load_entities(&self.api, &m_ids)?;
+
+        for f in files.values_mut() {
+            if let Err(e) = f.process(&self.api) {
+                if e.recovery() == Recovery::Abort {
+                    return Err(e);
+                }
+                warn!(file = %f.page_title(), "{}", e);
+            }
+        }
+
+        debug!("{:?}", files);
+        Ok(())
+    }
+}
+
+/// The bindings of a SPARQL result
+fn sparql_bindings(json: &Value) -> Result<&Vec<Value>> {
+    json["results"]["bindings"]
+        .as_array()
+        .ok_or_else(|| Error::Parse("No bindings in SPARQL results".to_string()))
+}
+
+/// PetScan is not a Wikimedia site, so it does not get the bot's credentials
+fn petscan(commons: &MW, psid: u64) -> Result<Value> {
+    let url = format!(
+        "{}?psid={}&format=json",
+        &commons.settings.api.petscan, psid
+    );
+    let petscan_result = commons.get_url(&url)?;
+    Ok(serde_json::from_str(&petscan_result)?)
+}
+
+/// The pages of a PetScan result
+fn petscan_pages(petscan_result: &Value) -> Result<&Vec<Value>> {
+    petscan_result["*"][0]["a"]["*"]
+        .as_array()
+        .ok_or_else(|| Error::Parse("No pages in PetScan result".to_string()))
+}
+
+pub fn depicts_german_buildings(commons: &mut MW, psid: u64) -> Result<()> {
+    let petscan_result = petscan(commons, psid)?;
+    let categories = petscan_pages(&petscan_result)?;
+    let mut cii: Vec<CategoryItemImage> = categories
+        .iter()
+        .filter_map(|c| match (c["title"].as_str(), c["q"].as_str()) {
+            (Some(title), Some(q)) => Some(CategoryItemImage {
+                category: title.to_string(),
+                item: Some(q.to_string()),
+                image: None,
+            }),
+            _ => None,
+        })
+        .collect();
+
+    // Load entities
+    commons.ec.load_entities(
+        &commons.api,
+        &cii.iter()
+            .map(|c| c.item.as_ref().unwrap().clone())
+            .collect(),
+    )?;
+
+    // Category item => main topic
+    let mut to_load: Vec<String> = vec![];
+    cii.iter_mut().for_each(|c| {
+        let entity = match commons.ec.get_entity(c.item.as_ref().unwrap()) {
+            Some(e) => e,
+            None => {
+                c.item = None;
+                return;
+            }
+        };
+        if !entity.has_target_entity("P31", "Q4167836") {
+            return;
+        }
+        match entity.values_for_property("P301").iter().nth(0) {
+            Some(target) => {
+                //println!("{} => {:?}", c.item.as_ref().unwrap(), target);
+                match target {
+                    wikibase::Value::Entity(e) => {
+                        c.item = Some(e.id().to_string());
+                        to_load.push(e.id().to_string());
+                    }
+                    _ => c.item = None,
+                }
+            }
+            None => c.item = None,
+        }
+    });
+    cii.retain(|c| c.item.is_some());
+
+    // Load remaining items
+    commons.ec.load_entities(&commons.api, &to_load)?;
+
+    // Get images
+    cii.iter_mut().for_each(|c| {
+        c.image = match commons.ec.get_entity(c.item.as_ref().unwrap().to_owned()) {
+            Some(item) => item
+                .values_for_property("P18")
+                .iter()
+                .filter_map(|i| match i {
+                    wikibase::Value::StringValue(s) => Some(s.to_owned()),
+                    _ => None,
+                })
+                .nth(0),
+            None => return,
+        };
+    });
+    cii.retain(|c| c.item.is_some() && c.image.is_some());
+
+    // Remove ones we had already
+    let found = cii.len();
+    cii.retain(|c| {
+        !commons.in_bot_log(c.item.as_ref().unwrap(), c.image.as_ref().unwrap(), "P180")
+    });
+    commons.report.add_candidates(found, found - cii.len());
+
+    // Paranoia
+    cii.retain(|c| match commons.ec.get_entity(c.item.as_ref().unwrap()) {
+        Some(entity) => !entity.has_target_entity("P31", "Q4167836"),
+        None => false,
+    });
+
+    let images: Vec<String> = cii.iter().filter_map(|c| c.image.to_owned()).collect();
+    commons.prefetch_files(&images)?;
+    commons.prefetch_exclusions(&images)?;
+
+    // Add "depicts" to files
+    for c in cii.iter() {
+        if let (Some(item), Some(image)) = (c.item.as_ref(), c.image.as_ref()) {
+            let span = info_span!("candidate", item = %item, file = %image);
+            let _enter = span.enter();
+            let entry = BotLogEntry::new(item, image, "P180", Some("German buildings"));
+            let result = commons.excluded(image).and_then(|excluded| {
+                if let Some(outcome) = excluded {
+                    return Ok(outcome);
+                }
+                commons.add_target_prominent(item, image, &"P180".to_string())
+            });
+            commons.log_outcome(entry, result)?;
+        }
+    }
+    commons.flush_edits()
+}
+
+pub fn depicts_p18_and_free_page_image(
+    commons: &mut MW,
+    sparql_part: &str,
+    server: &str,
+) -> Result<()> {
+    let local_wiki_api = commons.foreign_api(&format!("https://{}/w/api.php", &server))?;
+    let sparql = format!("SELECT ?q ?image ?article {{ {} . ?q  wdt:P18 ?image . ?article schema:about ?q ; schema:isPartOf <https://{}/> }}",&sparql_part,&server);
+    let wikidata = commons.foreign_api(&commons.settings.api.wikidata)?;
+    let json = commons.sparql_query(&wikidata, &sparql)?;
+
+    let mut iaipi: Vec<ItemArticleImagesPageImage> = sparql_bindings(&json)?
+        .iter()
+        .filter_map(|b| {
+            let (q, p18, article) = match (
+                b["q"]["value"].as_str(),
+                b["image"]["value"].as_str(),
+                b["article"]["value"].as_str(),
+            ) {
+                (Some(q), Some(i), Some(a)) => (q, i, a),
+                _ => return None,
+            };
+            Some(ItemArticleImagesPageImage {
+                q: wikidata.extract_entity_from_uri(q).ok()?,
+                p18: Some(MW::percent_decode_title(p18.split('/').last()?.to_string())),
+                article: MW::percent_decode_title(article.split('/').last()?.to_string()),
+                pageimage: None,
+            })
+        })
+        .collect();
+    let found = iaipi.len();
+    iaipi.retain(|i| !commons.in_bot_log(&i.q, i.p18.as_ref().unwrap(), "P180"));
+    commons.report.add_candidates(found, found - iaipi.len());
+    let images: Vec<String> = iaipi.iter().filter_map(|i| i.p18.to_owned()).collect();
+    commons.prefetch_files(&images)?;
+    commons.prefetch_exclusions(&images)?;
+
+    for x in iaipi.iter() {
+        let span = info_span!("candidate", item = %x.q, article = %x.article);
+        let _enter = span.enter();
+        if let Some(pageimage) = commons.get_free_page_image(&local_wiki_api, &x.article) {
+            if x.p18 == Some(pageimage.to_owned()) {
+                let entry = BotLogEntry::new(
+                    &x.q,
+                    &pageimage,
+                    "P180",
+                    Some(&format!("page image {}", &server)),
+                );
+                let result = commons.excluded(&pageimage).and_then(|excluded| {
+                    if let Some(outcome) = excluded {
+                        return Ok(outcome);
+                    }
+                    commons.add_target_prominent(&x.q, x.p18.as_ref().unwrap(), &"P180".to_string())
+                });
+                commons.log_outcome(entry, result)?;
+            }
+        }
+    }
+    commons.flush_edits()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bot_log::Outcome;
+    use crate::mock_api::*;
+
+    #[test]
+    fn petscan_without_credentials_offline() {
+        let mut wiki = mock_wiki();
+        wiki.user_rights = vec!["edit".to_string()];
+        wiki.petscan = json!({"*":[{"a":{"*":[{"title":"Foo.jpg","q":"Q1"}]}}]});
+        let mock = MockApi::start(wiki);
+        let mut settings = crate::settings::Settings::default();
+        settings.api.commons = mock.url.clone();
+        settings.api.petscan = mock.url.replace("/w/api.php", "/petscan");
+        settings.bot.log = temp_file("petscan.jsonl");
+        settings.bot.legacy_log = String::new();
+        let credentials = crate::Credentials::OAuth2 {
+            access_token: "abc".to_string(),
+        };
+        let commons = MW::new_with_credentials(&settings, &credentials).unwrap();
+        let result = petscan(&commons, 123).unwrap();
+        assert_eq!(petscan_pages(&result).unwrap().len(), 1);
+
+        let wiki = mock.wiki.lock().unwrap();
+        let petscan = wiki
+            .requests
+            .iter()
+            .find(|r| r.get("psid").map(|p| p.as_str()) == Some("123"))
+            .unwrap();
+        assert!(!petscan.contains_key("Authorization"));
+        // Unlike the requests to Commons
+        assert!(wiki
+            .requests_for("query")
+            .iter()
+            .all(|r| r["Authorization"] == "Bearer abc"));
+    }
+
+    fn outcomes(bot_log: &str) -> Vec<BotLogEntry> {
+        std::fs::read_to_string(bot_log)
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn job_file() {
+        let jobs = JobFile::from_toml(
+            r#"
+            [[job]]
+            query = "SELECT ?q ?image { ?q wdt:P1435 wd:Q17297633 ; wdt:P18 ?image }"
+            description = "Bavarian monuments"
+            skip_artwork = true
+            limit = 10
+            "#,
+        )
+        .unwrap();
+        assert_eq!(jobs.jobs.len(), 1);
+        let job = &jobs.jobs[0];
+        assert_eq!(job.property, "P180");
+        assert_eq!(job.rank, Rank::Preferred);
+        assert!(job.skip_artwork);
+        assert_eq!(job.limit, Some(10));
+        assert_eq!(job.on_duplicate, DuplicatePolicy::IgnoreRank);
+        assert_eq!(
+            job.summary_for("Q42", "Foo.jpg"),
+            "Used with P18 on Wikidata [[:d:Q42|]]"
+        );
+
+        // Rejected when the file is read, not with every edit
+        assert!(JobFile::from_toml(
+            r#"
+            [[job]]
+            query = "SELECT ?q ?image {}"
+            description = "typo"
+            rank = "prefered"
+            "#,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn resume_offline() {
+        let mock = MockApi::start(mock_wiki());
+        let bot_log = temp_file("resume.jsonl");
+        let run_state = temp_file("resume_state.jsonl");
+        let job = DepictsJob::new("SELECT ?q ?image {}", "test");
+        let candidates = vec![
+            ("Q1".to_string(), "Existing_file.jpg".to_string()),
+            ("Q3".to_string(), "Already_depicted.jpg".to_string()),
+        ];
+        // Killed after the first candidate
+        let mut state = RunState::create(&run_state, &job, &candidates).unwrap();
+        state.set_position(0).unwrap();
+        state
+            .record(
+                &BotLogEntry::new("Q1", "Existing_file.jpg", "P180", Some("test"))
+                    .with_outcome(Outcome::Added { revid: Some(1) }),
+            )
+            .unwrap();
+        // Resuming must not query SPARQL again
+        mock.wiki.lock().unwrap().sparql = Value::Null;
+
+        // A dry run must not mark the run as finished
+        let mut commons = mock_commons(&mock, &bot_log);
+        commons.run_state_file = Some(run_state.clone());
+        commons.dry_run = true;
+        commons.resume().unwrap();
+        let state = RunState::load(&run_state).unwrap();
+        assert!(!state.finished);
+        assert_eq!(state.position, 0);
+        assert!(!std::path::Path::new(&bot_log).exists());
+
+        let mut commons = mock_commons(&mock, &bot_log);
+        commons.run_state_file = Some(run_state.clone());
+        // Overrides the ignore_rank of the job
+        commons.duplicate_policy = DuplicatePolicy::UpgradeRank;
+        commons.resume().unwrap();
+
+        let logged: Vec<(String, Option<Outcome>)> = outcomes(&bot_log)
+            .into_iter()
+            .map(|e| (e.item, e.outcome))
+            .collect();
+        assert_eq!(
+            logged,
+            vec![(
+                "Q3".to_string(),
+                Some(Outcome::RankUpgraded { revid: Some(1) })
+            )]
+        );
+        assert!(mock
+            .wiki
+            .lock()
+            .unwrap()
+            .requests_for("wbeditentity")
+            .is_empty());
+        let state = RunState::load(&run_state).unwrap();
+        assert!(state.finished);
+        assert_eq!(state.position, 1);
+        assert!(state.pending().is_empty());
+    }
+
+    #[test]
+    fn depicts_p18_sparql_offline() {
+        let mock = MockApi::start(mock_wiki());
+        let bot_log = temp_file("depicts_p18_sparql.jsonl");
+        let mut commons = mock_commons(&mock, &bot_log);
+        commons.batch_edits = true;
+        commons
+            .depicts_p18_sparql("SELECT ?q ?image {}", "test", true)
+            .unwrap();
+
+        let mut logged: Vec<(String, Option<Outcome>)> = outcomes(&bot_log)
+            .into_iter()
+            .map(|e| (e.item, e.outcome))
+            .collect();
+        logged.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            logged,
+            vec![
+                ("Q1".to_string(), Some(Outcome::Added { revid: Some(1) })),
+                (
+                    "Q2".to_string(),
+                    Some(Outcome::Excluded {
+                        rule: "uses {{Artwork}}".to_string()
+                    })
+                ),
+                ("Q3".to_string(), Some(Outcome::AlreadyPresent)),
+                ("Q4".to_string(), Some(Outcome::PageMissing)),
+            ]
+        );
+        {
+            let wiki = mock.wiki.lock().unwrap();
+            assert_eq!(wiki.requests_for("wbeditentity").len(), 1);
+            // All files are looked up in one request
+            let lookups: Vec<&String> = wiki
+                .requests_for("query")
+                .into_iter()
+                .filter(|r| r.get("prop").map(|p| p.as_str()) == Some("info"))
+                .filter_map(|r| r.get("titles"))
+                .collect();
+            assert_eq!(lookups.len(), 1);
+            assert_eq!(lookups[0].split('|').count(), 4);
+            // ...and their statements and templates, too
+            assert_eq!(wiki.requests_for("wbgetentities").len(), 1);
+            let template_checks = wiki
+                .requests_for("query")
+                .into_iter()
+                .filter(|r| r.get("prop").map(|p| p.as_str()) == Some("templates"))
+                .count();
+            assert_eq!(template_checks, 1);
+            assert!(wiki.requests_for("wbgetclaims").is_empty());
+        }
+        let report = &commons.report;
+        assert_eq!((report.candidates, report.in_bot_log), (4, 0));
+        assert_eq!((report.edited, report.edits_sent), (1, 1));
+        assert_eq!(report.excluded, 1);
+        assert_eq!(report.already_present, 1);
+        assert_eq!(report.page_missing, 1);
+
+        // Only the missing file is tried again
+        mock.wiki.lock().unwrap().requests.clear();
+        let mut commons = mock_commons(&mock, &bot_log);
+        commons
+            .depicts_p18_sparql("SELECT ?q ?image {}", "test", true)
+            .unwrap();
+        let wiki = mock.wiki.lock().unwrap();
+        assert!(wiki.requests_for("wbeditentity").is_empty());
+        // Besides the exclusion templates, which every run looks up once
+        let lookups: Vec<&String> = wiki
+            .requests_for("query")
+            .iter()
+            .filter(|r| r.get("prop").map(|p| p.as_str()) != Some("redirects"))
+            .filter_map(|r| r.get("titles"))
+            .collect();
+        assert!(!lookups.is_empty());
+        assert!(lookups.iter().all(|t| *t == "File:Missing_file.jpg"));
+        assert_eq!(
+            (commons.report.candidates, commons.report.in_bot_log),
+            (4, 3)
+        );
+        assert_eq!(outcomes(&bot_log).len(), 5);
+    }
+}
@@ -1,7 +1,36 @@
+//! Adds structured data statements to files on Wikimedia Commons.
+//!
+//! `MW` is the Commons client; the `jobs` module holds the batches built on top of it.
+
+#[macro_use]
+extern crate serde_json;
+#[macro_use]
+extern crate serde_derive;
+#[macro_use]
+extern crate lazy_static;
+#[macro_use]
+extern crate tracing;
+
+pub mod bot_log;
+pub mod candidates;
+pub mod credentials;
+pub mod edit_batch;
+pub mod error;
+pub mod exclusions;
+pub mod jobs;
+pub mod media_ids;
 #[cfg(test)]
-mod tests {
-    #[test]
-    fn it_works() {
-        assert_eq!(2 + 2, 4);
-    }
-}
+pub(crate) mod mock_api;
+pub mod mw;
+pub mod report;
+pub mod retry;
+pub mod run_state;
+pub mod settings;
+pub mod statement;
+pub mod throttle;
+
+pub use crate::bot_log::{BotLog, BotLogEntry, Outcome};
+pub use crate::credentials::Credentials;
+pub use crate::error::{Error, Recovery};
+pub use crate::mw::MW;
+pub use crate::statement::{DuplicatePolicy, NewClaim, NewSnak, Rank};
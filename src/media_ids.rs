@@ -0,0 +1,164 @@
+//! MediaInfo IDs of many files at once
+//!
+//! The MediaInfo entity of a file is `M` followed by the page ID of its file
+//! page. `MW::resolve_media_ids` asks for the page IDs of up to 50 files per
+//! request (500 with `apihighlimits`) and keeps them here. Redirects are
+//! followed, so a renamed file resolves to the ID of its new page.
+
+use crate::bot_log::BotLog;
+use crate::error::{Error, Result};
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// What a file name resolved to
+#[derive(Debug, Clone, PartialEq)]
+pub enum MediaId {
+    /// `M<pageid>` of the file page, or of the page it redirects to
+    Id(String),
+    Missing,
+    /// Not a valid file name
+    Invalid,
+}
+
+/// File name, as `BotLog::normalize_file` has it => MediaInfo ID
+#[derive(Debug, Clone, Default)]
+pub struct MediaIds {
+    ids: HashMap<String, MediaId>,
+    /// File name => file the redirect leads to
+    redirects: HashMap<String, String>,
+}
+
+impl MediaIds {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, file: &str) -> Option<&MediaId> {
+        self.ids.get(&BotLog::normalize_file(file))
+    }
+
+    pub fn contains(&self, file: &str) -> bool {
+        self.get(file).is_some()
+    }
+
+    /// Where a file name redirects to, if it does
+    pub fn redirect_target(&self, file: &str) -> Option<&str> {
+        self.redirects
+            .get(&BotLog::normalize_file(file))
+            .map(|s| s.as_str())
+    }
+
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+
+    /// Adds the pages of an `action=query&prop=info&redirects=1` result for
+    /// `files`, following the title normalization and redirects the API reports
+    pub fn add_query_result(&mut self, files: &[String], j: &Value) -> Result<()> {
+        let pages = j["query"]["pages"]
+            .as_object()
+            .ok_or_else(|| Error::Parse(format!("No pages in query result: {}", j)))?;
+        let normalized = title_map(&j["query"]["normalized"]);
+        let redirects = title_map(&j["query"]["redirects"]);
+        let by_title: HashMap<String, MediaId> = pages
+            .values()
+            .filter_map(|page| {
+                let title = BotLog::normalize_file(page["title"].as_str()?);
+                Some((title, Self::media_id(page)))
+            })
+            .collect();
+
+        for file in files {
+            let file = BotLog::normalize_file(file);
+            let mut title = normalized.get(&file).unwrap_or(&file);
+            if let Some(target) = redirects.get(title) {
+                self.redirects.insert(file.to_owned(), target.to_owned());
+                title = target;
+            }
+            let media_id = by_title.get(title).cloned().unwrap_or(MediaId::Invalid);
+            self.ids.insert(file, media_id);
+        }
+        Ok(())
+    }
+
+    fn media_id(page: &Value) -> MediaId {
+        if page["invalid"].is_string() {
+            return MediaId::Invalid;
+        }
+        if page["missing"].is_string() {
+            return MediaId::Missing;
+        }
+        match page["pageid"].as_u64() {
+            Some(page_id) => MediaId::Id(format!("M{}", page_id)),
+            None => MediaId::Invalid,
+        }
+    }
+}
+
+/// The `from` => `to` pairs of a query result's `normalized` or `redirects`,
+/// as file names
+pub(crate) fn title_map(list: &Value) -> HashMap<String, String> {
+    list.as_array()
+        .map(|a| {
+            a.iter()
+                .filter_map(|n| {
+                    Some((
+                        BotLog::normalize_file(n["from"].as_str()?),
+                        BotLog::normalize_file(n["to"].as_str()?),
+                    ))
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn media_ids_from_query_result() {
+        let j = json!({"batchcomplete":"","query":{
+            "normalized":[
+                {"from":"File:foo_bar.jpg","to":"File:Foo bar.jpg"},
+                {"from":"File:Old_name.jpg","to":"File:Old name.jpg"}
+            ],
+            "redirects":[{"from":"File:Old name.jpg","to":"File:New name.jpg"}],
+            "pages":{
+                "12":{"pageid":12,"ns":6,"title":"File:Foo bar.jpg"},
+                "13":{"pageid":13,"ns":6,"title":"File:New name.jpg"},
+                "-1":{"ns":6,"title":"File:Gone.jpg","missing":""},
+                "-2":{"title":"File:<>","invalid":""}
+            }
+        }});
+        let files: Vec<String> = vec!["foo_bar.jpg", "Old_name.jpg", "Gone.jpg", "<>"]
+            .into_iter()
+            .map(|f| f.to_string())
+            .collect();
+        let mut ids = MediaIds::new();
+        ids.add_query_result(&files, &j).unwrap();
+
+        assert_eq!(ids.len(), 4);
+        assert_eq!(
+            ids.get("foo_bar.jpg"),
+            Some(&MediaId::Id("M12".to_string()))
+        );
+        assert_eq!(
+            ids.get("File:foo bar.jpg"),
+            Some(&MediaId::Id("M12".to_string()))
+        );
+        assert_eq!(
+            ids.get("Old name.jpg"),
+            Some(&MediaId::Id("M13".to_string()))
+        );
+        assert_eq!(ids.redirect_target("Old_name.jpg"), Some("New_name.jpg"));
+        assert_eq!(ids.redirect_target("foo_bar.jpg"), None);
+        assert_eq!(ids.get("Gone.jpg"), Some(&MediaId::Missing));
+        assert_eq!(ids.get("<>"), Some(&MediaId::Invalid));
+        assert!(!ids.contains("Other.jpg"));
+    }
+}
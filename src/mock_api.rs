@@ -0,0 +1,467 @@
+//! A local stand-in for the Commons and Wikidata APIs and the SPARQL endpoint, for tests
+
+use crate::mw::MW;
+use crate::settings::Settings;
+use crate::throttle::Throttle;
+use percent_encoding::percent_decode;
+use serde_json::Value;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// Pages, statements and SPARQL results served by `MockApi`
+#[derive(Debug, Clone, Default)]
+pub struct MockWiki {
+    /// Page title with namespace prefix and spaces => page ID
+    pub pages: HashMap<String, u64>,
+    /// Redirect title with namespace prefix and spaces => target title
+    pub redirects: HashMap<String, String>,
+    /// Page title => templates used, with "Template:" prefix
+    pub templates: HashMap<String, Vec<String>>,
+    /// Answer `prop=templates` for one page at a time, with continuation
+    pub paginate_templates: bool,
+    /// Page title => categories, with "Category:" prefix
+    pub categories: HashMap<String, Vec<String>>,
+    /// Entity ID => statements JSON
+    pub statements: HashMap<String, Vec<Value>>,
+    /// Returned for every SPARQL query
+    pub sparql: Value,
+    /// Returned for every request to `/petscan`
+    pub petscan: Value,
+    /// Parameters of every request, in order
+    pub requests: Vec<HashMap<String, String>>,
+    /// Rights of the logged-in user; no user is logged in if empty
+    pub user_rights: Vec<String>,
+    /// Action => responses served, in order, before any real answer
+    pub failures: HashMap<String, Vec<Value>>,
+    /// Action => (HTTP status, `Retry-After` seconds) served, in order, before
+    /// any other answer
+    pub http_failures: HashMap<String, Vec<(u16, u64)>>,
+    base_url: String,
+    lastrevid: u64,
+}
+
+impl MockWiki {
+    pub fn add_page(&mut self, title: &str, page_id: u64) {
+        self.pages.insert(title.replace('_', " "), page_id);
+    }
+
+    pub fn requests_for(&self, action: &str) -> Vec<&HashMap<String, String>> {
+        self.requests
+            .iter()
+            .filter(|r| r.get("action").map(|a| a.as_str()) == Some(action))
+            .collect()
+    }
+
+    fn http_failure(&mut self, params: &HashMap<String, String>) -> Option<(u16, u64)> {
+        let failures = self.http_failures.get_mut(params.get("action")?)?;
+        if failures.is_empty() {
+            return None;
+        }
+        Some(failures.remove(0))
+    }
+
+    fn respond(&mut self, path: &str, params: &HashMap<String, String>) -> Value {
+        if path.ends_with("/sparql") {
+            return self.sparql.clone();
+        }
+        if path.ends_with("/petscan") {
+            return self.petscan.clone();
+        }
+        let param = |key: &str| params.get(key).map(|s| s.as_str()).unwrap_or("");
+        if let Some(failures) = self.failures.get_mut(param("action")) {
+            if !failures.is_empty() {
+                return failures.remove(0);
+            }
+        }
+        match (param("action"), param("meta"), param("prop")) {
+            ("query", "siteinfo", _) => self.siteinfo(),
+            ("query", "userinfo", _) => self.userinfo(),
+            ("query", "tokens", _) => json!({"query":{"tokens":{"csrftoken":"mock+\\"}}}),
+            ("query", _, "pageprops") | ("query", _, "info") => {
+                self.pages_json(param("titles"), param("redirects") == "1", |_| json!({}))
+            }
+            ("query", _, "redirects") => {
+                let redirects = self.redirects.clone();
+                self.pages_json(param("titles"), false, |title| {
+                    let from: Vec<Value> = redirects
+                        .iter()
+                        .filter(|(_, target)| target.as_str() == title)
+                        .map(|(from, _)| json!({ "title": from }))
+                        .collect();
+                    json!({ "redirects": from })
+                })
+            }
+            ("query", _, prop) if prop == "templates" || prop == "categories" => {
+                let (links, prefix) = match prop {
+                    "templates" => (self.templates.clone(), "tl"),
+                    _ => (self.categories.clone(), "cl"),
+                };
+                // Like the API without apihighlimits, which drops the rest with a warning
+                let wanted: Vec<&str> = param(&format!("{}{}", prefix, prop))
+                    .split('|')
+                    .take(50)
+                    .collect();
+                let skip: usize = param(&format!("{}continue", prefix)).parse().unwrap_or(0);
+                let limit = if self.paginate_templates && prop == "templates" {
+                    1
+                } else {
+                    usize::MAX
+                };
+                // Pages using any of the wanted templates or categories so far
+                let using = Cell::new(0);
+                let mut j = self.pages_json(param("titles"), false, |title| {
+                    let used: Vec<Value> = links
+                        .get(title)
+                        .map(|t| t.to_owned())
+                        .unwrap_or_default()
+                        .iter()
+                        .filter(|t| wanted.contains(&t.as_str()))
+                        .map(|t| json!({ "title": t }))
+                        .collect();
+                    if used.is_empty() {
+                        return json!({});
+                    }
+                    let num = using.get();
+                    using.set(num + 1);
+                    if num < skip || num - skip >= limit {
+                        json!({})
+                    } else {
+                        json!({ prop: used })
+                    }
+                });
+                if using.get().saturating_sub(skip) > limit {
+                    j["continue"] = json!({
+                        format!("{}continue", prefix): (skip + limit).to_string(),
+                        "continue": "||"
+                    });
+                }
+                j
+            }
+            ("wbgetclaims", _, _) => {
+                let statements = self.statements.get(param("entity")).cloned();
+                let property = param("property");
+                let claims: Vec<Value> = statements
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter(|s| s["mainsnak"]["property"].as_str() == Some(property))
+                    .collect();
+                json!({"claims":{ property: claims }})
+            }
+            ("wbgetentities", _, _) => {
+                let mut entities = serde_json::Map::new();
+                param("ids").split('|').for_each(|id| {
+                    entities.insert(id.to_string(), self.entity_json(id));
+                });
+                json!({"entities":entities,"success":1})
+            }
+            ("wbeditentity", _, _) => {
+                let id = param("id").to_string();
+                let data: Value = serde_json::from_str(param("data")).unwrap();
+                let new_claims = data["claims"].as_array().cloned().unwrap_or_default();
+                let statements = self.statements.entry(id.clone()).or_insert_with(Vec::new);
+                new_claims.into_iter().for_each(|mut claim| {
+                    claim["id"] = json!(format!("{}${}", &id, statements.len() + 1));
+                    statements.push(claim);
+                });
+                self.lastrevid += 1;
+                json!({"entity":{"id":id,"lastrevid":self.lastrevid},"success":1})
+            }
+            ("wbsetclaim", _, _) => {
+                let claim: Value = serde_json::from_str(param("claim")).unwrap();
+                let id = claim["id"].as_str().unwrap_or("").to_string();
+                let entity = id.split('$').next().unwrap_or("").to_string();
+                if let Some(statements) = self.statements.get_mut(&entity) {
+                    statements
+                        .iter_mut()
+                        .filter(|s| s["id"].as_str() == Some(id.as_str()))
+                        .for_each(|s| *s = claim.clone());
+                }
+                self.lastrevid += 1;
+                json!({"pageinfo":{"lastrevid":self.lastrevid},"success":1,"claim":claim})
+            }
+            _ => json!({"error":{"code":"mock-unsupported","info":format!("{:?}", params)}}),
+        }
+    }
+
+    fn userinfo(&self) -> Value {
+        if self.user_rights.is_empty() {
+            return json!({"query":{"userinfo":{"id":0,"name":"127.0.0.1","anon":""}}});
+        }
+        json!({"query":{"userinfo":{"id":1,"name":"MockBot","rights":self.user_rights}}})
+    }
+
+    fn siteinfo(&self) -> Value {
+        json!({"query":{
+            "general":{
+                "mainpage":"Main Page",
+                "base":format!("{}/wiki/Main_Page", &self.base_url),
+                "sitename":"Mock",
+                "articlepath":"/wiki/$1",
+                "scriptpath":"/w",
+                "server":&self.base_url,
+                "servername":"127.0.0.1",
+                "lang":"en",
+                "wikiid":"mockwiki",
+                "wikibase-conceptbaseuri":"http://www.wikidata.org/entity/",
+                "wikibase-sparql":format!("{}/sparql", &self.base_url)
+            },
+            "namespaces":{
+                "0":{"id":0,"case":"first-letter","content":"","*":""},
+                "6":{"id":6,"case":"first-letter","canonical":"File","*":"File"},
+                "10":{"id":10,"case":"first-letter","canonical":"Template","*":"Template"},
+                "14":{"id":14,"case":"first-letter","canonical":"Category","*":"Category"}
+            },
+            "namespacealiases":[],
+            "libraries":[],
+            "extensions":[],
+            "statistics":{}
+        }})
+    }
+
+    fn pages_json<F: Fn(&str) -> Value>(
+        &self,
+        titles: &str,
+        follow_redirects: bool,
+        extra: F,
+    ) -> Value {
+        let mut pages = serde_json::Map::new();
+        let mut normalized = vec![];
+        let mut redirects = vec![];
+        let mut missing: i64 = 0;
+        titles.split('|').for_each(|title| {
+            let from = title;
+            let title = title.replace('_', " ");
+            if title != from {
+                normalized.push(json!({"from": from, "to": title}));
+            }
+            let title = match self.redirects.get(&title) {
+                Some(target) if follow_redirects => {
+                    redirects.push(json!({"from": title, "to": target}));
+                    target.to_owned()
+                }
+                _ => title,
+            };
+            let mut page = extra(&title);
+            page["title"] = json!(title);
+            page["ns"] = json!(6);
+            if self.redirects.contains_key(&title) {
+                page["redirect"] = json!("");
+            }
+            match self.pages.get(&title) {
+                Some(page_id) => {
+                    page["pageid"] = json!(page_id);
+                    pages.insert(page_id.to_string(), page);
+                }
+                None => {
+                    missing -= 1;
+                    page["missing"] = json!("");
+                    pages.insert(missing.to_string(), page);
+                }
+            }
+        });
+        json!({"batchcomplete":"","query":{
+            "normalized": normalized,
+            "redirects": redirects,
+            "pages": pages
+        }})
+    }
+
+    fn entity_json(&self, id: &str) -> Value {
+        let page_id = id.trim_start_matches('M').parse::<u64>().unwrap_or(0);
+        if !self.pages.values().any(|p| *p == page_id) {
+            return json!({"id":id,"missing":""});
+        }
+        let mut claims = serde_json::Map::new();
+        self.statements
+            .get(id)
+            .cloned()
+            .unwrap_or_default()
+            .into_iter()
+            .for_each(|s| {
+                let property = s["mainsnak"]["property"].as_str().unwrap_or("").to_string();
+                let list = claims.entry(property).or_insert_with(|| json!([]));
+                if let Some(a) = list.as_array_mut() {
+                    a.push(s);
+                }
+            });
+        json!({"type":"mediainfo","id":id,"labels":{},"descriptions":{},"statements":claims})
+    }
+}
+
+/// HTTP server on a local port, answering from a shared `MockWiki`
+pub struct MockApi {
+    /// API URL to pass to `MW::new` and `Api::new_from_builder`
+    pub url: String,
+    pub wiki: Arc<Mutex<MockWiki>>,
+}
+
+impl MockApi {
+    pub fn start(mut wiki: MockWiki) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("Could not bind mock API");
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        wiki.base_url = base_url.clone();
+        let wiki = Arc::new(Mutex::new(wiki));
+        let server_wiki = wiki.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let _ = Self::handle(stream, &server_wiki);
+            }
+        });
+        Self {
+            url: format!("{}/w/api.php", base_url),
+            wiki,
+        }
+    }
+
+    fn handle(mut stream: TcpStream, wiki: &Arc<Mutex<MockWiki>>) -> std::io::Result<()> {
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line)?;
+        let mut content_length = 0;
+        let mut authorization = None;
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line)?;
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            let lower = line.to_lowercase();
+            if lower.starts_with("content-length:") {
+                content_length = lower[15..].trim().parse().unwrap_or(0);
+            }
+            if lower.starts_with("authorization:") {
+                authorization = Some(line[14..].trim().to_string());
+            }
+        }
+        let mut body = vec![0; content_length];
+        reader.read_exact(&mut body)?;
+
+        let target = request_line.split_whitespace().nth(1).unwrap_or("/");
+        let (path, query) = match target.find('?') {
+            Some(pos) => (&target[..pos], &target[pos + 1..]),
+            None => (target, ""),
+        };
+        let mut params = Self::parse_form(query);
+        params.extend(Self::parse_form(&String::from_utf8_lossy(&body)));
+        // Not an API parameter; recorded so tests can check it
+        if let Some(authorization) = authorization {
+            params.insert("Authorization".to_string(), authorization);
+        }
+
+        let (status, retry_after, response) = {
+            let mut wiki = wiki.lock().unwrap();
+            if !path.ends_with("/sparql") {
+                wiki.requests.push(params.clone());
+            }
+            match wiki.http_failure(&params) {
+                Some((status, retry_after)) => (status, Some(retry_after), String::new()),
+                None => (200, None, wiki.respond(path, &params).to_string()),
+            }
+        };
+        let retry_after = retry_after
+            .map(|seconds| format!("Retry-After: {}\r\n", seconds))
+            .unwrap_or_default();
+        write!(
+            stream,
+            "HTTP/1.1 {} Mock\r\nContent-Type: application/json; charset=utf-8\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            retry_after,
+            response.len(),
+            response
+        )?;
+        stream.flush()
+    }
+
+    fn parse_form(s: &str) -> HashMap<String, String> {
+        s.split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let mut kv = pair.splitn(2, '=');
+                let decode = |x: Option<&str>| {
+                    percent_decode(x.unwrap_or("").replace('+', " ").as_bytes())
+                        .decode_utf8_lossy()
+                        .to_string()
+                };
+                (decode(kv.next()), decode(kv.next()))
+            })
+            .collect()
+    }
+}
+
+/// A unique path in the temp directory; any old file there is removed
+pub fn temp_file(name: &str) -> String {
+    let path = std::env::temp_dir().join(format!(
+        "commons_statements_{}_{}",
+        std::process::id(),
+        name
+    ));
+    let _ = std::fs::remove_file(&path);
+    path.to_string_lossy().to_string()
+}
+
+/// An `MW` talking to the mock, without edit delay, logging to `bot_log`
+pub fn mock_commons(mock: &MockApi, bot_log: &str) -> MW {
+    let mut settings = Settings::default();
+    settings.api.commons = mock.url.clone();
+    settings.api.wikidata = mock.url.clone();
+    settings.bot.log = bot_log.to_string();
+    settings.bot.legacy_log = String::new();
+    let mut commons = MW::new_with_settings(&settings, MW::get_builder()).unwrap();
+    commons.throttle = Throttle::unlimited();
+    commons.retry.initial_backoff = std::time::Duration::from_millis(1);
+    commons
+}
+
+/// Three files (one artwork, one already depicting Q3) and a SPARQL result
+/// pointing at them and at a missing file
+pub fn mock_wiki() -> MockWiki {
+    let mut wiki = MockWiki::default();
+    wiki.add_page("File:Existing file.jpg", 101);
+    wiki.add_page("File:Painting.jpg", 102);
+    wiki.add_page("File:Already_depicted.jpg", 103);
+    wiki.templates.insert(
+        "File:Painting.jpg".to_string(),
+        vec!["Template:Artwork".to_string()],
+    );
+    wiki.statements.insert(
+        "M103".to_string(),
+        vec![json!({
+            "mainsnak":{
+                "snaktype":"value",
+                "property":"P180",
+                "datavalue":{
+                    "value":{"entity-type":"item","numeric-id":3,"id":"Q3"},
+                    "type":"wikibase-entityid"
+                }
+            },
+            "type":"statement",
+            "id":"M103$1",
+            "rank":"normal"
+        })],
+    );
+    let binding = |q: &str, image: &str| {
+        json!({
+            "q":{"type":"uri","value":format!("http://www.wikidata.org/entity/{}", q)},
+            "image":{
+                "type":"uri",
+                "value":format!("http://commons.wikimedia.org/wiki/Special:FilePath/{}", image)
+            }
+        })
+    };
+    wiki.sparql = json!({
+        "head":{"vars":["q","image"]},
+        "results":{"bindings":[
+            binding("Q1", "Existing%20file.jpg"),
+            binding("Q2", "Painting.jpg"),
+            binding("Q3", "Already%20depicted.jpg"),
+            binding("Q4", "Missing%20file.jpg")
+        ]}
+    });
+    wiki
+}
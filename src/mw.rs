@@ -0,0 +1,1535 @@
+//! Client for Commons and its MediaInfo entities
+
+use crate::bot_log::{BotLog, BotLogEntry, Outcome};
+use crate::credentials::Credentials;
+use crate::edit_batch::EditBatch;
+use crate::error::{Error, Recovery, Result};
+use crate::exclusions::Exclusion;
+use crate::media_ids::{title_map, MediaId, MediaIds};
+use crate::report::RunReport;
+use crate::retry::{parse_retry_after, Response, RetryPolicy};
+use crate::run_state::RunState;
+use crate::settings::{layered_config, Settings};
+use crate::statement::{merge_qualifiers, upgrade_rank, DuplicatePolicy, NewClaim, NewSnak, Rank};
+use crate::throttle::Throttle;
+use percent_encoding::percent_decode;
+use reqwest::header::{RETRY_AFTER, USER_AGENT};
+use reqwest::StatusCode;
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+use wikibase::entity_container::EntityContainer;
+use wikibase::mediawiki::api::{Api, NamespaceID};
+use wikibase::mediawiki::title::Title;
+use wikibase::{Entity, EntityType, EntityValue, SnakType};
+
+/// `wbgetentities` takes at most this many IDs per request
+const ENTITIES_PER_QUERY: usize = 50;
+
+#[derive(Debug, Clone)]
+pub struct MW {
+    pub api: Api,
+    pub ec: EntityContainer,
+    pub bot_log: BotLog,
+    /// Simulate all writes; payloads end up in `dry_run_edits` instead
+    pub dry_run: bool,
+    pub dry_run_edits: Vec<Value>,
+    /// Collect claims per MediaInfo entity and send them with `flush_edits`
+    pub batch_edits: bool,
+    /// What `add_target_prominent` does if the file already has the statement
+    pub duplicate_policy: DuplicatePolicy,
+    pub edit_batch: EditBatch,
+    /// Endpoints, bot log path and summary tag
+    pub settings: Settings,
+    /// Depicts jobs checkpoint to this file, for `resume`
+    pub run_state_file: Option<String>,
+    /// The checkpoints of the job that is running
+    pub run_state: Option<RunState>,
+    /// What happened to the candidates so far
+    pub report: RunReport,
+    /// Applies to all queries and edits made through `MW`
+    pub retry: RetryPolicy,
+    /// Paces edits; the mediawiki crate's own edit delay is not used
+    pub throttle: Throttle,
+    /// Whether the account has the `bot` right; edits are flagged then
+    pub is_bot: bool,
+    /// Whether the account has the `apihighlimits` right; more titles per query then
+    pub apihighlimits: bool,
+    /// MediaInfo IDs of the files looked up so far
+    pub media_ids: MediaIds,
+    /// MediaInfo ID => `statements` JSON, from `prefetch_statements`; dropped on edit
+    pub statement_cache: HashMap<String, Value>,
+    /// File name => what template or category rule it matched, from `prefetch_exclusions`
+    pub exclusion_cache: HashMap<String, Option<Exclusion>>,
+    /// Templates of the exclusion rules and their redirects, once looked up
+    exclusion_templates: Option<HashMap<String, String>>,
+    /// Fetched for the first edit, and again if the API rejects it
+    edit_token: Option<String>,
+}
+
+impl MW {
+    /// Default settings, with `api_url` for Commons
+    pub fn new(api_url: &str) -> Result<Self> {
+        let mut settings = Settings::default();
+        settings.api.commons = api_url.to_string();
+        Self::new_with_settings(&settings, Self::get_builder())
+    }
+
+    pub fn new_with_settings(settings: &Settings, builder: reqwest::ClientBuilder) -> Result<Self> {
+        let mut ret = Self {
+            api: Api::new_from_builder(&settings.api.commons, builder)?,
+            ec: EntityContainer::new(),
+            bot_log: BotLog::open(&settings.bot.log)?,
+            dry_run: false,
+            dry_run_edits: vec![],
+            batch_edits: false,
+            duplicate_policy: DuplicatePolicy::IgnoreRank,
+            settings: settings.to_owned(),
+            edit_batch: EditBatch::new(50),
+            run_state_file: None,
+            run_state: None,
+            report: RunReport::start(),
+            retry: RetryPolicy::default(),
+            throttle: Throttle::new(settings.throttle.to_owned()),
+            is_bot: false,
+            apihighlimits: false,
+            media_ids: MediaIds::new(),
+            statement_cache: HashMap::new(),
+            exclusion_cache: HashMap::new(),
+            exclusion_templates: None,
+            edit_token: None,
+        };
+        ret.api.set_edit_delay(None);
+        ret.api.set_user_agent(settings.api.user_agent.as_str());
+        ret.ec.allow_special_entity_data(false);
+        // Pairs from the old free-form log count as done, too
+        let legacy_log = &settings.bot.legacy_log;
+        if !legacy_log.is_empty() {
+            if let Err(e) = ret.bot_log.import_legacy(legacy_log, false) {
+                warn!("Could not read legacy bot log {}: {}", legacy_log, e);
+            }
+        }
+        Ok(ret)
+    }
+
+    pub fn get_builder() -> reqwest::ClientBuilder {
+        reqwest::ClientBuilder::new().timeout(Duration::from_secs(240))
+    }
+
+    /// Settings and credentials from `filename`, the environment and
+    /// `overrides`; see `settings::layered_config`
+    pub fn new_from_ini_file(filename: &str, overrides: &[(String, String)]) -> Result<Self> {
+        let config = layered_config(filename, overrides)?;
+        let settings = Settings::from_config(&config)?;
+        let credentials = Credentials::from_config(&config)?;
+        Self::new_with_credentials(&settings, &credentials)
+    }
+
+    /// Logs in, and checks that the credentials work
+    pub fn new_with_credentials(settings: &Settings, credentials: &Credentials) -> Result<Self> {
+        let builder = credentials.client_builder(Self::get_builder())?;
+        let mut ret = Self::new_with_settings(settings, builder)?;
+        credentials.apply(&mut ret.api)?;
+        ret.load_user_rights()?;
+        Ok(ret)
+    }
+
+    /// Sets `is_bot` from the rights of the logged-in user
+    pub fn load_user_rights(&mut self) -> Result<()> {
+        let params = self.api.params_into(&vec![
+            ("action", "query"),
+            ("meta", "userinfo"),
+            ("uiprop", "rights"),
+        ]);
+        let j = self.query_json(&self.api, params)?;
+        let userinfo = &j["query"]["userinfo"];
+        if userinfo["anon"].is_string() || userinfo["id"].as_u64() == Some(0) {
+            return Err(Error::Login("Not logged in".to_string()));
+        }
+        let has_right = |right: &str| {
+            userinfo["rights"]
+                .as_array()
+                .map(|rights| rights.iter().any(|r| r.as_str() == Some(right)))
+                .unwrap_or(false)
+        };
+        self.is_bot = has_right("bot");
+        self.apihighlimits = has_right("apihighlimits");
+        debug!(user = ?userinfo["name"], is_bot = self.is_bot, "logged in");
+        Ok(())
+    }
+
+    pub fn in_bot_log(&self, item: &str, file: &str, property: &str) -> bool {
+        self.bot_log.contains(item, file, property)
+    }
+
+    pub fn append_log(&mut self, entry: BotLogEntry) -> Result<()> {
+        debug!(?entry, "bot log");
+        if let Some(outcome) = &entry.outcome {
+            self.report.add_outcome(outcome);
+        }
+        if self.dry_run {
+            return Ok(());
+        }
+        if let Some(state) = self.run_state.as_mut() {
+            state.record(&entry)?;
+        }
+        self.bot_log.append(entry)
+    }
+
+    pub fn api_query_prop2(
+        &self,
+        key1: &str,
+        value1: &str,
+        key2: &str,
+        value2: &String,
+    ) -> Result<Value> {
+        let params: HashMap<String, String> =
+            vec![("action", "query"), (key1, value1), (key2, value2.as_str())]
+                .iter()
+                .map(|x| (x.0.to_string(), x.1.to_string()))
+                .collect();
+        self.query_json(&self.api, params)
+    }
+
+    /// Runs a query on `api` with `maxlag`, trying again after transient errors.
+    /// An error in the response becomes `Error::Api`.
+    pub fn query_json(&self, api: &Api, mut params: HashMap<String, String>) -> Result<Value> {
+        self.retry.add_maxlag(&mut params);
+        self.retry
+            .run(true, |_previous| Self::send_request(api, &params, "GET"))
+    }
+
+    /// Like `query_json`, but POSTed; for parameters too long for a URL
+    pub fn post_query_json(&self, api: &Api, mut params: HashMap<String, String>) -> Result<Value> {
+        self.retry.add_maxlag(&mut params);
+        self.retry
+            .run(true, |_previous| Self::send_request(api, &params, "POST"))
+    }
+
+    /// Sends an API request. Unlike `Api::query_api_json`, this keeps the
+    /// `Retry-After` header; server errors and HTTP 429 become `Error::Http`.
+    fn send_request(api: &Api, params: &HashMap<String, String>, method: &str) -> Result<Response> {
+        let mut params = params.clone();
+        params.insert("format".to_string(), "json".to_string());
+        let mut response = api.query_raw_response(api.api_url(), &params, method)?;
+        let retry_after = response
+            .headers()
+            .get(RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(parse_retry_after);
+        let status = response.status();
+        if status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS {
+            return Err(Error::Http {
+                status: status.as_u16(),
+                retry_after,
+            });
+        }
+        let json = serde_json::from_str(&response.text()?)?;
+        Ok(Response { json, retry_after })
+    }
+
+    /// How many titles the API takes in one query
+    pub fn titles_per_query(&self) -> usize {
+        if self.apihighlimits {
+            500
+        } else {
+            50
+        }
+    }
+
+    /// Looks up the MediaInfo IDs of the `files` not in `media_ids` yet,
+    /// `titles_per_query` at a time
+    pub fn resolve_media_ids(&mut self, files: &[String]) -> Result<()> {
+        let mut todo: Vec<String> = files
+            .iter()
+            .filter(|f| !self.media_ids.contains(f))
+            .map(|f| BotLog::normalize_file(f))
+            .collect();
+        todo.sort();
+        todo.dedup();
+        for chunk in todo.chunks(self.titles_per_query()) {
+            let titles: Vec<String> = chunk.iter().map(|f| format!("File:{}", f)).collect();
+            let params = self.api.params_into(&vec![
+                ("action", "query"),
+                ("prop", "info"),
+                ("redirects", "1"),
+                ("titles", titles.join("|").as_str()),
+            ]);
+            let j = self.post_query_json(&self.api, params)?;
+            self.media_ids.add_query_result(chunk, &j)?;
+        }
+        debug!(files = todo.len(), "resolved MediaInfo IDs");
+        Ok(())
+    }
+
+    /// Loads the statements of the MediaInfo entities not in `statement_cache`
+    /// yet, `ENTITIES_PER_QUERY` at a time
+    pub fn prefetch_statements(&mut self, media_ids: &[String]) -> Result<()> {
+        let mut todo: Vec<String> = media_ids
+            .iter()
+            .filter(|id| !self.statement_cache.contains_key(*id))
+            .cloned()
+            .collect();
+        todo.sort();
+        todo.dedup();
+        for chunk in todo.chunks(ENTITIES_PER_QUERY) {
+            let params = self.api.params_into(&vec![
+                ("action", "wbgetentities"),
+                ("ids", chunk.join("|").as_str()),
+                ("props", "claims"),
+            ]);
+            let j = self.query_json(&self.api, params)?;
+            for id in chunk {
+                let entity = &j["entities"][id];
+                // Left out ones are looked up again when needed
+                if !entity.is_object() {
+                    continue;
+                }
+                // A MediaInfo entity without any statements is "missing"
+                self.statement_cache
+                    .insert(id.to_owned(), entity["statements"].to_owned());
+            }
+        }
+        debug!(entities = todo.len(), "prefetched statements");
+        Ok(())
+    }
+
+    /// Looks up the MediaInfo IDs and statements of `files` ahead of the edits.
+    /// What could not be fetched in bulk is tried again one file at a time.
+    pub fn prefetch_files(&mut self, files: &[String]) -> Result<()> {
+        match self.try_prefetch_files(files) {
+            Err(e) if e.recovery() == Recovery::Abort => Err(e),
+            Err(e) => {
+                warn!("Could not prefetch files in bulk: {}", e);
+                Ok(())
+            }
+            Ok(()) => Ok(()),
+        }
+    }
+
+    fn try_prefetch_files(&mut self, files: &[String]) -> Result<()> {
+        self.resolve_media_ids(files)?;
+        let media_ids: Vec<String> = files
+            .iter()
+            .filter_map(|file| match self.media_ids.get(file) {
+                Some(MediaId::Id(id)) => Some(id.to_owned()),
+                _ => None,
+            })
+            .collect();
+        self.prefetch_statements(&media_ids)
+    }
+
+    /// The MediaInfo ID of a file, looked up unless `resolve_media_ids` already did
+    pub fn media_id(&mut self, file: &str) -> Result<MediaId> {
+        if !self.media_ids.contains(file) {
+            self.resolve_media_ids(&[file.to_string()])?;
+        }
+        self.media_ids
+            .get(file)
+            .cloned()
+            .ok_or_else(|| Error::Parse(format!("No MediaInfo ID for {}", file)))
+    }
+
+    /// The page ID of `title`, or of the page it redirects to
+    pub fn get_page_id(&self, title: &Title) -> Result<NamespaceID> {
+        self.get_page_id_if_exists(title)?
+            .ok_or_else(|| Error::MissingPage(title.pretty().to_string()))
+    }
+
+    /// Like `get_page_id`, but `None` if the page does not exist
+    pub fn get_page_id_if_exists(&self, title: &Title) -> Result<Option<NamespaceID>> {
+        let full_title = title
+            .full_with_underscores(&self.api)
+            .ok_or_else(|| Error::Parse(format!("No namespace for title {:?}", &title)))?;
+        let params = self.api.params_into(&vec![
+            ("action", "query"),
+            ("prop", "info"),
+            ("redirects", "1"),
+            ("titles", full_title.as_str()),
+        ]);
+        let res = self.query_json(&self.api, params)?;
+        let pages = res["query"]["pages"].as_object().ok_or_else(|| {
+            Error::Parse(format!("get_page_id: No object.pages in JSON: {}", res))
+        })?;
+        for (page_id, _page) in pages {
+            return match page_id.parse::<NamespaceID>() {
+                Ok(ret) => {
+                    if ret < 0 {
+                        Ok(None)
+                    } else {
+                        Ok(Some(ret))
+                    }
+                }
+                Err(_) => Err(Error::Parse(format!("Can't parse NamespaceID {}", page_id))),
+            };
+        }
+        Err(Error::Parse(format!(
+            "get_page_id: No page ID in JSON: {}",
+            res
+        )))
+    }
+
+    pub fn load_entity<S: Into<String>>(&mut self, entity_id: S) -> Result<Entity> {
+        Ok(self.ec.load_entity(&self.api, entity_id)?)
+    }
+
+    pub fn wbcreateclaim(
+        self: &mut Self,
+        entity: &String,
+        snaktype: SnakType,
+        valuetype: &str,
+        property: &String,
+        value: &wikibase::Value,
+        summary: Option<String>,
+        baserevid: Option<u64>,
+        rank: Option<Rank>,
+    ) -> Result<Value> {
+        let mut claim = NewClaim::new(NewSnak::new(property, snaktype, valuetype, value));
+        if let Some(rank) = rank {
+            claim.rank = rank;
+        }
+        self.add_claims(entity, &[claim], summary, baserevid)
+    }
+
+    /// Adds claims, with their qualifiers and references, in a single `wbeditentity` call
+    pub fn add_claims(
+        &mut self,
+        entity: &str,
+        claims: &[NewClaim],
+        summary: Option<String>,
+        baserevid: Option<u64>,
+    ) -> Result<Value> {
+        let mut params: HashMap<String, String> = HashMap::new();
+
+        let claims: Vec<Value> = claims.iter().map(|c| c.to_json()).collect();
+        let j = json!({ "claims": claims });
+        let j = ::serde_json::to_string(&j)?;
+
+        params.insert("action".to_string(), "wbeditentity".to_string());
+        params.insert("id".to_string(), entity.to_string());
+        params.insert("data".to_string(), j);
+        self.add_summary(&mut params, summary);
+        self.add_baserevid(&mut params, baserevid);
+        self.add_bot_flag(&mut params);
+
+        let ret = self.edit(params);
+        if ret.is_ok() {
+            self.ec.remove_entity(entity.to_owned());
+            self.statement_cache.remove(entity);
+        }
+        ret
+    }
+
+    /// Sends an edit, or records it if in dry-run mode. The edit token is added here.
+    /// An error in the response becomes `Error::Api`.
+    fn edit(&mut self, mut params: HashMap<String, String>) -> Result<Value> {
+        if self.dry_run {
+            let payload = json!(params);
+            self.dry_run_edits.push(payload.clone());
+            return Ok(json!({"success":1,"dry_run":payload}));
+        }
+        let retry = self.retry.clone();
+        retry.add_maxlag(&mut params);
+        let ret = retry.run(false, |previous| {
+            match previous {
+                Some(e) if e.api_code() == Some("badtoken") => self.edit_token = None,
+                Some(e) if e.api_code() == Some("maxlag") => self.throttle.slow_down(),
+                Some(Error::Http { status: 429, .. }) => self.throttle.slow_down(),
+                _ => {}
+            }
+            self.throttle.wait();
+            self.add_edit_token(&mut params)?;
+            Self::send_request(&self.api, &params, "POST")
+        });
+        if ret.is_ok() {
+            self.throttle.speed_up();
+            self.report.edits_sent += 1;
+        }
+        ret
+    }
+
+    fn add_edit_token(self: &mut Self, params: &mut HashMap<String, String>) -> Result<()> {
+        if self.edit_token.is_none() {
+            self.edit_token = Some(self.api.get_edit_token()?);
+        }
+        if let Some(token) = &self.edit_token {
+            params.insert("token".to_string(), token.to_owned());
+        }
+        Ok(())
+    }
+
+    fn add_bot_flag(&self, params: &mut HashMap<String, String>) {
+        if self.is_bot {
+            params.insert("bot".to_string(), "1".to_string());
+        }
+    }
+
+    fn add_baserevid(&self, params: &mut HashMap<String, String>, baserevid: Option<u64>) {
+        match baserevid {
+            Some(baserevid) => {
+                params.insert("baserevid".to_string(), baserevid.to_string());
+            }
+            None => {}
+        }
+    }
+
+    /// Sets the summary, tagged with `bot.summary_tag`
+    fn add_summary(&self, params: &mut HashMap<String, String>, summary: Option<String>) {
+        match summary {
+            Some(mut s) => {
+                let tag = &self.settings.bot.summary_tag;
+                if !tag.is_empty() && !s.contains(tag.as_str()) {
+                    s = format!("{} {}", s, tag);
+                }
+                params.insert("summary".to_string(), s);
+            }
+            None => {}
+        }
+    }
+
+    /// A client for another wiki, with the configured user agent
+    pub fn foreign_api(&self, api_url: &str) -> Result<Api> {
+        let mut api = Api::new_from_builder(api_url, Self::get_builder())?;
+        api.set_user_agent(self.settings.api.user_agent.as_str());
+        Ok(api)
+    }
+
+    /// GETs a URL that is not a MediaWiki API, like PetScan's, with a client
+    /// that does not carry the Commons credentials
+    pub fn get_url(&self, url: &str) -> Result<String> {
+        let client = Self::get_builder().build()?;
+        let mut response = client
+            .get(url)
+            .header(USER_AGENT, self.settings.api.user_agent.as_str())
+            .send()?;
+        Ok(response.text()?)
+    }
+
+    /// Runs a query on `api.sparql`, or the endpoint `wikidata` announces
+    pub fn sparql_query(&self, wikidata: &Api, sparql: &str) -> Result<Value> {
+        match &self.settings.api.sparql {
+            Some(endpoint) => {
+                let params = wikidata.params_into(&vec![("query", sparql), ("format", "json")]);
+                let result = wikidata.query_raw(endpoint, &params, "POST")?;
+                Ok(serde_json::from_str(&result)?)
+            }
+            None => Ok(wikidata.sparql_query(sparql)?),
+        }
+    }
+
+    pub fn get_free_page_image(&self, mw_api: &Api, page: &String) -> Option<String> {
+        self.query_json(
+            mw_api,
+            mw_api.params_into(&vec![
+                ("action", "query"),
+                ("prop", "pageprops"),
+                ("titles", page.as_str()),
+            ]),
+        )
+        .ok()?["query"]["pages"]
+            .as_object()?
+            .iter()
+            .filter_map(|(_pageid, pagedata)| pagedata["pageprops"]["page_image_free"].as_str())
+            .map(|s| s.to_string())
+            .nth(0)
+    }
+
+    pub fn page_contains_template(&self, page: &String, template: &str) -> bool {
+        match self.query_json(
+            &self.api,
+            self.api.params_into(&vec![
+                ("action", "query"),
+                ("prop", "templates"),
+                ("tltemplates", format!("Template:{}", template).as_str()),
+                ("titles", page.as_str()),
+            ]),
+        ) {
+            Ok(j) => match j["query"]["pages"].as_object() {
+                Some(pages) => pages
+                    .iter()
+                    .any(|(_pageid, pagedata)| pagedata["templates"].is_array()),
+                None => false,
+            },
+
+            _ => false,
+        }
+    }
+
+    /// Runs a query, following `continue` until the API has sent everything;
+    /// `f` gets each response
+    fn query_continued<F>(&self, mut params: HashMap<String, String>, mut f: F) -> Result<()>
+    where
+        F: FnMut(&Value) -> Result<()>,
+    {
+        loop {
+            let j = self.post_query_json(&self.api, params.clone())?;
+            f(&j)?;
+            match j["continue"].as_object() {
+                Some(cont) => cont.iter().for_each(|(key, value)| {
+                    let value = match value.as_str() {
+                        Some(s) => s.to_string(),
+                        None => value.to_string(),
+                    };
+                    params.insert(key.to_string(), value);
+                }),
+                None => return Ok(()),
+            }
+        }
+    }
+
+    /// Which of `titles` each of `files` links to with `prop` (`templates` or
+    /// `categories`, with `prefix` `tl` or `cl`), `titles_per_query` files and
+    /// as many of `titles` per request. The keys are the file names as
+    /// `BotLog::normalize_file` has them.
+    fn links_used(
+        &self,
+        files: &[String],
+        prop: &str,
+        prefix: &str,
+        titles: &[String],
+    ) -> Result<HashMap<String, HashSet<String>>> {
+        let mut files: Vec<String> = files.iter().map(|f| BotLog::normalize_file(f)).collect();
+        files.sort();
+        files.dedup();
+
+        let mut ret = HashMap::new();
+        let limit_key = format!("{}limit", prefix);
+        let filter_key = format!("{}{}", prefix, prop);
+        for chunk in files.chunks(self.titles_per_query()) {
+            let pages: Vec<String> = chunk.iter().map(|f| format!("File:{}", f)).collect();
+            let mut normalized: HashMap<String, String> = HashMap::new();
+            let mut by_title: HashMap<String, HashSet<String>> = HashMap::new();
+            // The API ignores values of `tltemplates`/`clcategories` past the limit
+            for titles in titles.chunks(self.titles_per_query()) {
+                let params = self.api.params_into(&vec![
+                    ("action", "query"),
+                    ("prop", prop),
+                    (limit_key.as_str(), "max"),
+                    (filter_key.as_str(), titles.join("|").as_str()),
+                    ("titles", pages.join("|").as_str()),
+                ]);
+                self.query_continued(params, |j| {
+                    normalized.extend(title_map(&j["query"]["normalized"]));
+                    let pages = j["query"]["pages"]
+                        .as_object()
+                        .ok_or_else(|| Error::Parse(format!("No pages in query result: {}", j)))?;
+                    for page in pages.values() {
+                        let title = match page["title"].as_str() {
+                            Some(title) => BotLog::normalize_file(title),
+                            None => continue,
+                        };
+                        let used = by_title.entry(title).or_insert_with(HashSet::new);
+                        page[prop]
+                            .as_array()
+                            .into_iter()
+                            .flatten()
+                            .filter_map(|t| t["title"].as_str())
+                            .for_each(|t| {
+                                used.insert(t.to_string());
+                            });
+                    }
+                    Ok(())
+                })?;
+            }
+            for file in chunk {
+                let title = normalized.get(file).unwrap_or(file);
+                let used = by_title.get(title).cloned().unwrap_or_default();
+                ret.insert(file.to_owned(), used);
+            }
+        }
+        Ok(ret)
+    }
+
+    /// Which of `templates` (without namespace prefix) each of `files` uses,
+    /// `titles_per_query` files per request. The keys are the file names as
+    /// `BotLog::normalize_file` has them.
+    pub fn templates_used(
+        &self,
+        files: &[String],
+        templates: &[&str],
+    ) -> Result<HashMap<String, HashSet<String>>> {
+        let titles: Vec<String> = templates
+            .iter()
+            .map(|t| format!("Template:{}", t))
+            .collect();
+        Ok(self
+            .links_used(files, "templates", "tl", &titles)?
+            .into_iter()
+            .map(|(file, used)| {
+                let used = used
+                    .iter()
+                    .map(|t| t.trim_start_matches("Template:").to_string())
+                    .collect();
+                (file, used)
+            })
+            .collect())
+    }
+
+    /// Template name, without namespace prefix => name of the template it is or
+    /// redirects to, for the `templates` of the exclusion rules; looked up once
+    fn exclusion_templates(&mut self) -> Result<HashMap<String, String>> {
+        if let Some(templates) = &self.exclusion_templates {
+            return Ok(templates.to_owned());
+        }
+        let mut ret = HashMap::new();
+        let names = self.settings.exclude.templates.to_owned();
+        for chunk in names.chunks(self.titles_per_query()) {
+            let titles: Vec<String> = chunk.iter().map(|t| format!("Template:{}", t)).collect();
+            let params = self.api.params_into(&vec![
+                ("action", "query"),
+                ("prop", "redirects"),
+                ("rdnamespace", "10"),
+                ("rdlimit", "max"),
+                ("titles", titles.join("|").as_str()),
+            ]);
+            let mut redirects = vec![];
+            self.query_continued(params, |j| {
+                j["query"]["pages"]
+                    .as_object()
+                    .into_iter()
+                    .flat_map(|pages| pages.values())
+                    .for_each(|page| {
+                        let target = page["title"].as_str().unwrap_or("").to_string();
+                        page["redirects"]
+                            .as_array()
+                            .into_iter()
+                            .flatten()
+                            .filter_map(|r| r["title"].as_str())
+                            .for_each(|r| redirects.push((r.to_string(), target.to_owned())));
+                    });
+                Ok(())
+            })?;
+            for (title, name) in titles.iter().zip(chunk.iter()) {
+                ret.insert(name.to_owned(), name.to_owned());
+                redirects
+                    .iter()
+                    .filter(|(_, target)| *target == title.replace('_', " "))
+                    .for_each(|(redirect, _)| {
+                        let redirect = redirect.trim_start_matches("Template:");
+                        ret.insert(redirect.to_string(), name.to_owned());
+                    });
+            }
+        }
+        debug!(
+            templates = ret.len(),
+            "exclusion templates and their redirects"
+        );
+        self.exclusion_templates = Some(ret.to_owned());
+        Ok(ret)
+    }
+
+    /// Checks `files` against the template and category exclusion rules in
+    /// bulk, for `exclusion`. Renamed files are checked under their new name.
+    /// Files the check fails for are tried again one at a time.
+    pub fn prefetch_exclusions(&mut self, files: &[String]) -> Result<()> {
+        match self.check_exclusions(files) {
+            Err(e) if e.recovery() == Recovery::Abort => Err(e),
+            Err(e) => {
+                warn!("Could not check exclusion rules in bulk: {}", e);
+                Ok(())
+            }
+            Ok(()) => Ok(()),
+        }
+    }
+
+    fn check_exclusions(&mut self, files: &[String]) -> Result<()> {
+        let targets: HashMap<String, String> = files
+            .iter()
+            .map(|f| {
+                let target = self
+                    .media_ids
+                    .redirect_target(f)
+                    .unwrap_or(f.as_str())
+                    .to_string();
+                (BotLog::normalize_file(f), BotLog::normalize_file(&target))
+            })
+            .collect();
+        let to_check: Vec<String> = targets.values().cloned().collect();
+
+        let templates = self.exclusion_templates()?;
+        let template_names: Vec<&str> = templates.keys().map(|t| t.as_str()).collect();
+        let used_templates = if template_names.is_empty() {
+            HashMap::new()
+        } else {
+            self.templates_used(&to_check, &template_names)?
+        };
+        let categories: Vec<String> = self
+            .settings
+            .exclude
+            .categories
+            .iter()
+            .map(|c| format!("Category:{}", c))
+            .collect();
+        let used_categories = if categories.is_empty() {
+            HashMap::new()
+        } else {
+            self.links_used(&to_check, "categories", "cl", &categories)?
+        };
+
+        for (file, target) in targets {
+            let template = used_templates
+                .get(&target)
+                .into_iter()
+                .flatten()
+                .filter_map(|t| templates.get(t))
+                .min()
+                .map(|name| Exclusion::Template(name.to_owned()));
+            let category = used_categories
+                .get(&target)
+                .into_iter()
+                .flatten()
+                .min()
+                .map(|c| Exclusion::Category(c.trim_start_matches("Category:").to_string()));
+            self.exclusion_cache.insert(file, template.or(category));
+        }
+        Ok(())
+    }
+
+    /// Why a file should not get statements, if it should not: a template or
+    /// category of the exclusion rules, or a statement for one of their properties
+    pub fn exclusion(&mut self, file: &str) -> Result<Option<Exclusion>> {
+        let key = BotLog::normalize_file(file);
+        if !self.exclusion_cache.contains_key(&key) {
+            self.check_exclusions(&[key.to_owned()])?;
+        }
+        if let Some(Some(exclusion)) = self.exclusion_cache.get(&key) {
+            return Ok(Some(exclusion.to_owned()));
+        }
+        let media_id = match self.media_id(file)? {
+            MediaId::Id(id) => id,
+            _ => return Ok(None),
+        };
+        for property in self.settings.exclude.properties.to_owned() {
+            if !self.get_statements(&media_id, &property)?.is_empty() {
+                return Ok(Some(Exclusion::Property(property)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// `Outcome::Excluded` with the rule that leaves out a file, if one does; logs why
+    pub fn excluded(&mut self, file: &str) -> Result<Option<Outcome>> {
+        match self.exclusion(file)? {
+            Some(exclusion) => {
+                info!(file = %file, "Leaving out file: {}", exclusion);
+                Ok(Some(Outcome::Excluded {
+                    rule: exclusion.to_string(),
+                }))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Whether a file, with or without `File:` prefix, uses {{Artwork}}
+    pub fn is_artwork(&self, file: &String) -> bool {
+        let page = match self.media_ids.redirect_target(file) {
+            Some(target) => format!("File:{}", target),
+            None => format!("File:{}", BotLog::normalize_file(file)),
+        };
+        self.page_contains_template(&page, "Artwork")
+    }
+
+    pub fn percent_decode_title(s: String) -> String {
+        percent_decode(s.as_bytes())
+            .decode_utf8_lossy()
+            .replace(' ', "_")
+            .to_string()
+    }
+
+    pub fn add_target_prominent(
+        &mut self,
+        source_item: &String,
+        filename: &String,
+        property: &String,
+    ) -> Result<Outcome> {
+        let summary = format!("Used with P18 on Wikidata [[:d:{}|]]", &source_item);
+        let policy = self.duplicate_policy;
+        self.add_target(
+            source_item,
+            filename,
+            property,
+            Rank::Preferred,
+            summary,
+            policy,
+        )
+    }
+
+    pub fn add_target(
+        &mut self,
+        source_item: &String,
+        filename: &String,
+        property: &String,
+        rank: Rank,
+        summary: String,
+        policy: DuplicatePolicy,
+    ) -> Result<Outcome> {
+        let new_value =
+            wikibase::Value::Entity(EntityValue::new(EntityType::Item, source_item.clone()));
+
+        let media_id = match self.media_id(filename)? {
+            MediaId::Id(id) => id,
+            MediaId::Missing | MediaId::Invalid => return Ok(Outcome::PageMissing),
+        };
+        if let Some(target) = self.media_ids.redirect_target(filename) {
+            info!(file = %filename, target = %target, "File was renamed; P18 is stale");
+            self.report.add_stale_p18(source_item, filename, target);
+        }
+
+        let claim = NewClaim::new(NewSnak::value(property, new_value)).with_rank(rank);
+        self.ensure_statement(&media_id, claim, summary, policy)
+    }
+
+    /// Adds a statement to an entity unless a statement with the same property and
+    /// value exists; `policy` decides what happens to such an existing statement.
+    pub fn ensure_statement(
+        &mut self,
+        media_id: &str,
+        claim: NewClaim,
+        summary: String,
+        policy: DuplicatePolicy,
+    ) -> Result<Outcome> {
+        let existing = self.get_statements(media_id, &claim.main_snak.property)?;
+        let mut matching: Vec<Value> = existing
+            .into_iter()
+            .filter(|statement| claim.main_snak.matches(&statement["mainsnak"]))
+            .collect();
+
+        if matching.is_empty() {
+            if self.batch_edits {
+                self.queue_claim(media_id, claim, summary)?;
+                return Ok(Outcome::Queued {
+                    media_id: media_id.to_string(),
+                });
+            }
+            let res = self.add_claims(media_id, &[claim], Some(summary), None)?;
+            return Ok(Outcome::from_edit_result(&res));
+        }
+
+        match policy {
+            DuplicatePolicy::IgnoreRank => Ok(Outcome::AlreadyPresent),
+            DuplicatePolicy::UpgradeRank => {
+                let statement = &mut matching[0];
+                if !upgrade_rank(statement, claim.rank) {
+                    return Ok(Outcome::AlreadyPresent);
+                }
+                let res = self.wbsetclaim(media_id, statement, Some(summary))?;
+                Ok(Outcome::from_edit_result_with(&res, |revid| {
+                    Outcome::RankUpgraded { revid }
+                }))
+            }
+            DuplicatePolicy::MergeQualifiers => {
+                let statement = &mut matching[0];
+                if !merge_qualifiers(statement, &claim.qualifiers) {
+                    return Ok(Outcome::AlreadyPresent);
+                }
+                let res = self.wbsetclaim(media_id, statement, Some(summary))?;
+                Ok(Outcome::from_edit_result_with(&res, |revid| {
+                    Outcome::QualifiersMerged { revid }
+                }))
+            }
+        }
+    }
+
+    /// Statements of an entity for a property, as JSON; prefetched ones
+    /// come from `statement_cache`
+    pub fn get_statements(&self, entity: &str, property: &str) -> Result<Vec<Value>> {
+        if let Some(statements) = self.statement_cache.get(entity) {
+            return Ok(statements[property]
+                .as_array()
+                .map(|a| a.to_owned())
+                .unwrap_or_default());
+        }
+        let params = self.api.params_into(&vec![
+            ("action", "wbgetclaims"),
+            ("entity", entity),
+            ("property", property),
+        ]);
+        let j = match self.query_json(&self.api, params) {
+            Ok(j) => j,
+            // A MediaInfo entity without any statements does not exist yet
+            Err(ref e) if e.api_code() == Some("no-such-entity") => return Ok(vec![]),
+            Err(e) => return Err(e),
+        };
+        Ok(j["claims"][property]
+            .as_array()
+            .map(|a| a.to_owned())
+            .unwrap_or_default())
+    }
+
+    /// Replaces an existing statement, identified by its `id`
+    pub fn wbsetclaim(
+        &mut self,
+        entity: &str,
+        statement: &Value,
+        summary: Option<String>,
+    ) -> Result<Value> {
+        let mut params: HashMap<String, String> = HashMap::new();
+        params.insert("action".to_string(), "wbsetclaim".to_string());
+        params.insert("claim".to_string(), ::serde_json::to_string(statement)?);
+        self.add_summary(&mut params, summary);
+        self.add_bot_flag(&mut params);
+
+        let ret = self.edit(params);
+        if ret.is_ok() {
+            self.ec.remove_entity(entity.to_owned());
+            self.statement_cache.remove(entity);
+        }
+        ret
+    }
+
+    /// Adds a claim to the edit batch, sending the batch first if it is full
+    pub fn queue_claim(&mut self, media_id: &str, claim: NewClaim, summary: String) -> Result<()> {
+        if self.edit_batch.is_full() && !self.edit_batch.contains(media_id) {
+            self.flush_edits()?;
+        }
+        self.edit_batch.add_claim(media_id, claim, summary);
+        Ok(())
+    }
+
+    /// Sends all batched claims, one `wbeditentity` per MediaInfo entity,
+    /// and logs the outcome for the candidates that led to them. Fails only
+    /// if the run should be aborted.
+    pub fn flush_edits(&mut self) -> Result<()> {
+        for (media_id, pending) in self.edit_batch.take() {
+            let span = info_span!("batch", media_id = %media_id);
+            let _enter = span.enter();
+            let result = self
+                .add_claims(&media_id, &pending.claims, Some(pending.summary()), None)
+                .map(|res| Outcome::from_edit_result(&res));
+            let outcome = self.outcome_or_abort(&media_id, result)?;
+            for entry in pending.entries {
+                self.append_log(entry.with_outcome(outcome.clone()))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the outcome of an attempted edit to the bot log. Queued edits
+    /// are logged once the batch is sent. Failures that are not worth going on
+    /// after are returned; all others end up in the log, to be tried again
+    /// in a later run.
+    pub fn log_outcome(&mut self, entry: BotLogEntry, result: Result<Outcome>) -> Result<()> {
+        if let Ok(Outcome::Queued { media_id }) = &result {
+            self.edit_batch.add_entry(media_id, entry);
+            return Ok(());
+        }
+        let outcome =
+            self.outcome_or_abort(&format!("{} / {}", &entry.item, &entry.file), result)?;
+        self.append_log(entry.with_outcome(outcome))
+    }
+
+    fn outcome_or_abort(&self, what: &str, result: Result<Outcome>) -> Result<Outcome> {
+        match result {
+            Ok(outcome) => Ok(outcome),
+            Err(e) => {
+                warn!(%what, class = %e.class(), "{}", e);
+                if e.recovery() == Recovery::Abort {
+                    return Err(e);
+                }
+                Ok(Outcome::from_error(&e))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_api::*;
+
+    #[test]
+    fn get_page_id_offline() {
+        let mut wiki = mock_wiki();
+        wiki.add_page("File:Old name.jpg", 104);
+        wiki.redirects.insert(
+            "File:Old name.jpg".to_string(),
+            "File:Existing file.jpg".to_string(),
+        );
+        let mock = MockApi::start(wiki);
+        let commons = mock_commons(&mock, &temp_file("get_page_id.jsonl"));
+        assert_eq!(
+            commons
+                .get_page_id(&Title::new("Existing_file.jpg", 6))
+                .unwrap(),
+            101
+        );
+        // The renamed file's current page, not the redirect
+        assert_eq!(
+            commons.get_page_id(&Title::new("Old_name.jpg", 6)).unwrap(),
+            101
+        );
+        assert!(commons
+            .get_page_id(&Title::new("Missing_file.jpg", 6))
+            .is_err());
+        assert_eq!(
+            commons
+                .get_page_id_if_exists(&Title::new("Missing_file.jpg", 6))
+                .unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn resolve_media_ids_offline() {
+        let mut wiki = mock_wiki();
+        wiki.add_page("File:Old name.jpg", 104);
+        wiki.redirects.insert(
+            "File:Old name.jpg".to_string(),
+            "File:Existing file.jpg".to_string(),
+        );
+        (0..60).for_each(|num| wiki.add_page(&format!("File:Bulk {}.jpg", num), 200 + num));
+        let mock = MockApi::start(wiki);
+        let mut commons = mock_commons(&mock, &temp_file("resolve_media_ids.jsonl"));
+        let mut files: Vec<String> = (0..60).map(|num| format!("Bulk_{}.jpg", num)).collect();
+        files.push("Existing_file.jpg".to_string());
+        files.push("Old_name.jpg".to_string());
+        files.push("Missing_file.jpg".to_string());
+        commons.resolve_media_ids(&files).unwrap();
+
+        assert_eq!(commons.media_ids.len(), 63);
+        assert_eq!(
+            commons.media_id("Bulk 7.jpg").unwrap(),
+            MediaId::Id("M207".to_string())
+        );
+        assert_eq!(
+            commons.media_id("Existing_file.jpg").unwrap(),
+            MediaId::Id("M101".to_string())
+        );
+        assert_eq!(
+            commons.media_id("Old_name.jpg").unwrap(),
+            MediaId::Id("M101".to_string())
+        );
+        assert_eq!(
+            commons.media_id("Missing_file.jpg").unwrap(),
+            MediaId::Missing
+        );
+        // The renamed file is edited, and its old name reported
+        let outcome = commons
+            .add_target_prominent(
+                &"Q9".to_string(),
+                &"Old_name.jpg".to_string(),
+                &"P180".to_string(),
+            )
+            .unwrap();
+        assert_eq!(outcome, Outcome::Added { revid: Some(1) });
+        assert_eq!(
+            mock.wiki.lock().unwrap().requests_for("wbeditentity")[0]["id"],
+            "M101"
+        );
+        assert_eq!(commons.report.stale_p18.len(), 1);
+        assert_eq!(commons.report.stale_p18[0].target, "Existing_file.jpg");
+        // 63 titles take two queries; nothing is looked up again
+        commons.resolve_media_ids(&files).unwrap();
+        let wiki = mock.wiki.lock().unwrap();
+        let lookups = wiki
+            .requests_for("query")
+            .into_iter()
+            .filter(|r| r.get("prop").map(|p| p.as_str()) == Some("info"))
+            .count();
+        assert_eq!(lookups, 2);
+
+        commons.apihighlimits = true;
+        assert_eq!(commons.titles_per_query(), 500);
+    }
+
+    #[test]
+    fn prefetch_statements_offline() {
+        let mock = MockApi::start(mock_wiki());
+        let mut commons = mock_commons(&mock, &temp_file("prefetch_statements.jsonl"));
+        let p180 = "P180".to_string();
+        let files: Vec<String> = vec![
+            "Existing_file.jpg",
+            "Already_depicted.jpg",
+            "Missing_file.jpg",
+        ]
+        .into_iter()
+        .map(|f| f.to_string())
+        .collect();
+        commons.prefetch_files(&files).unwrap();
+        assert_eq!(commons.statement_cache.len(), 2);
+
+        let outcome = commons
+            .add_target_prominent(&"Q3".to_string(), &files[1], &p180)
+            .unwrap();
+        assert_eq!(outcome, Outcome::AlreadyPresent);
+        let outcome = commons
+            .add_target_prominent(&"Q1".to_string(), &files[0], &p180)
+            .unwrap();
+        assert_eq!(outcome, Outcome::Added { revid: Some(1) });
+        {
+            let wiki = mock.wiki.lock().unwrap();
+            assert_eq!(wiki.requests_for("wbgetentities").len(), 1);
+            assert!(wiki.requests_for("wbgetclaims").is_empty());
+        }
+
+        // The edited entity is not taken from the cache any more
+        let outcome = commons
+            .add_target_prominent(&"Q1".to_string(), &files[0], &p180)
+            .unwrap();
+        assert_eq!(outcome, Outcome::AlreadyPresent);
+        assert_eq!(
+            mock.wiki.lock().unwrap().requests_for("wbgetclaims").len(),
+            1
+        );
+    }
+
+    #[test]
+    fn oauth2_login_offline() {
+        let mut wiki = mock_wiki();
+        wiki.user_rights = vec!["edit".to_string(), "bot".to_string()];
+        let mock = MockApi::start(wiki);
+        let credentials = Credentials::OAuth2 {
+            access_token: "abc".to_string(),
+        };
+        let mut settings = Settings::default();
+        settings.api.commons = mock.url.clone();
+        settings.bot.log = temp_file("oauth2_login.jsonl");
+        settings.bot.legacy_log = String::new();
+        let commons = MW::new_with_credentials(&settings, &credentials).unwrap();
+        assert!(commons.is_bot);
+        let wiki = mock.wiki.lock().unwrap();
+        let userinfo: Vec<&HashMap<String, String>> = wiki
+            .requests
+            .iter()
+            .filter(|r| r.get("meta").map(|m| m.as_str()) == Some("userinfo"))
+            .collect();
+        assert_eq!(userinfo.len(), 1);
+        assert_eq!(userinfo[0]["Authorization"], "Bearer abc");
+
+        // The API does not know the token
+        let mock = MockApi::start(mock_wiki());
+        settings.api.commons = mock.url.clone();
+        let e = MW::new_with_credentials(&settings, &credentials).unwrap_err();
+        assert_eq!(e.recovery(), Recovery::Abort);
+    }
+
+    #[test]
+    fn sparql_endpoint_offline() {
+        let mock = MockApi::start(mock_wiki());
+        let mut commons = mock_commons(&mock, &temp_file("sparql_endpoint.jsonl"));
+        let wikidata = commons.foreign_api(&mock.url).unwrap();
+        commons.settings.api.sparql = Some(mock.url.replace("/w/api.php", "/mirror/sparql"));
+        let json = commons.sparql_query(&wikidata, "SELECT ?q {}").unwrap();
+        let wiki = mock.wiki.lock().unwrap();
+        assert_eq!(json, wiki.sparql);
+        // The configured endpoint is used without asking the wiki for one
+        assert!(!wiki
+            .requests
+            .iter()
+            .any(|r| r.get("meta").map(|m| m.as_str()) == Some("siteinfo")));
+    }
+
+    #[test]
+    fn add_target_prominent_offline() {
+        let mock = MockApi::start(mock_wiki());
+        let mut commons = mock_commons(&mock, &temp_file("add_target.jsonl"));
+        let p180 = "P180".to_string();
+
+        let outcome = commons
+            .add_target_prominent(&"Q1".to_string(), &"Existing_file.jpg".to_string(), &p180)
+            .unwrap();
+        assert_eq!(outcome, Outcome::Added { revid: Some(1) });
+        {
+            let wiki = mock.wiki.lock().unwrap();
+            let edits = wiki.requests_for("wbeditentity");
+            assert_eq!(edits.len(), 1);
+            assert_eq!(edits[0]["id"], "M101");
+            assert_eq!(
+                edits[0]["summary"],
+                "Used with P18 on Wikidata [[:d:Q1|]] #rust_commons_statement"
+            );
+            let data: Value = serde_json::from_str(&edits[0]["data"]).unwrap();
+            assert_eq!(data["claims"][0]["rank"], json!("preferred"));
+            assert_eq!(
+                data["claims"][0]["mainsnak"]["datavalue"]["value"]["id"],
+                json!("Q1")
+            );
+        }
+
+        let outcome = commons
+            .add_target_prominent(&"Q1".to_string(), &"Existing_file.jpg".to_string(), &p180)
+            .unwrap();
+        assert_eq!(outcome, Outcome::AlreadyPresent);
+
+        commons.duplicate_policy = DuplicatePolicy::UpgradeRank;
+        let outcome = commons
+            .add_target_prominent(
+                &"Q3".to_string(),
+                &"Already_depicted.jpg".to_string(),
+                &p180,
+            )
+            .unwrap();
+        assert_eq!(outcome, Outcome::RankUpgraded { revid: Some(2) });
+        assert_eq!(
+            mock.wiki.lock().unwrap().statements["M103"][0]["rank"],
+            json!("preferred")
+        );
+        assert_eq!(
+            mock.wiki.lock().unwrap().requests_for("wbeditentity").len(),
+            1
+        );
+        // The wbeditentity and the wbsetclaim
+        assert_eq!(commons.report.edits_sent, 2);
+    }
+
+    #[test]
+    fn retry_on_maxlag_and_badtoken() {
+        let mut wiki = mock_wiki();
+        wiki.failures.insert(
+            "wbgetclaims".to_string(),
+            vec![json!({"error":{"code":"maxlag","info":"Waiting for a database server","lag":0}})],
+        );
+        wiki.failures.insert(
+            "wbeditentity".to_string(),
+            vec![json!({"error":{"code":"badtoken","info":"Invalid CSRF token."}})],
+        );
+        let mock = MockApi::start(wiki);
+        let mut commons = mock_commons(&mock, &temp_file("retry.jsonl"));
+        let outcome = commons
+            .add_target_prominent(
+                &"Q1".to_string(),
+                &"Existing_file.jpg".to_string(),
+                &"P180".to_string(),
+            )
+            .unwrap();
+        assert_eq!(outcome, Outcome::Added { revid: Some(1) });
+
+        let wiki = mock.wiki.lock().unwrap();
+        let claims = wiki.requests_for("wbgetclaims");
+        assert_eq!(claims.len(), 2);
+        assert!(claims.iter().all(|r| r["maxlag"] == "5"));
+        assert_eq!(wiki.requests_for("wbeditentity").len(), 2);
+        let token_requests = wiki
+            .requests
+            .iter()
+            .filter(|r| r.get("meta").map(|m| m.as_str()) == Some("tokens"))
+            .count();
+        assert_eq!(token_requests, 2);
+    }
+
+    #[test]
+    fn retry_on_http_errors() {
+        let mut wiki = mock_wiki();
+        wiki.http_failures
+            .insert("wbgetclaims".to_string(), vec![(503, 0)]);
+        wiki.http_failures
+            .insert("wbeditentity".to_string(), vec![(429, 0)]);
+        let mock = MockApi::start(wiki);
+        let mut commons = mock_commons(&mock, &temp_file("retry_http.jsonl"));
+        let outcome = commons
+            .add_target_prominent(
+                &"Q1".to_string(),
+                &"Existing_file.jpg".to_string(),
+                &"P180".to_string(),
+            )
+            .unwrap();
+        assert_eq!(outcome, Outcome::Added { revid: Some(1) });
+        {
+            let wiki = mock.wiki.lock().unwrap();
+            assert_eq!(wiki.requests_for("wbgetclaims").len(), 2);
+            // Turned away, so sent again even though it is an edit
+            assert_eq!(wiki.requests_for("wbeditentity").len(), 2);
+        }
+
+        mock.wiki
+            .lock()
+            .unwrap()
+            .http_failures
+            .insert("wbgetclaims".to_string(), vec![(503, 7)]);
+        commons.retry.max_attempts = 1;
+        let params = commons.api.params_into(&vec![
+            ("action", "wbgetclaims"),
+            ("entity", "M101"),
+            ("property", "P180"),
+        ]);
+        match commons.query_json(&commons.api, params) {
+            Err(Error::Http {
+                status,
+                retry_after,
+            }) => {
+                assert_eq!(status, 503);
+                assert_eq!(retry_after, Some(Duration::from_secs(7)));
+            }
+            other => panic!("Expected HTTP 503, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn log_outcome_skips_or_aborts() {
+        let mock = MockApi::start(mock_wiki());
+        let bot_log = temp_file("log_outcome.jsonl");
+        let mut commons = mock_commons(&mock, &bot_log);
+        let entry = BotLogEntry::new("Q1", "Foo.jpg", "P180", None);
+        commons
+            .log_outcome(
+                entry.clone(),
+                Err(Error::MissingPage("File:Foo.jpg".to_string())),
+            )
+            .unwrap();
+        assert!(std::fs::read_to_string(&bot_log)
+            .unwrap()
+            .contains("page_missing"));
+        // Worth another try later
+        assert!(!commons.in_bot_log("Q1", "Foo.jpg", "P180"));
+        assert!(commons
+            .log_outcome(entry, Err(Error::Config("No token".to_string())))
+            .is_err());
+    }
+
+    #[test]
+    fn is_artwork_offline() {
+        let mock = MockApi::start(mock_wiki());
+        let commons = mock_commons(&mock, &temp_file("is_artwork.jsonl"));
+        assert!(commons.is_artwork(&"File:Painting.jpg".to_string()));
+        assert!(!commons.is_artwork(&"File:Existing_file.jpg".to_string()));
+    }
+
+    #[test]
+    fn templates_used_offline() {
+        let mut wiki = mock_wiki();
+        wiki.add_page("File:Photo.jpg", 104);
+        wiki.templates.insert(
+            "File:Photo.jpg".to_string(),
+            vec![
+                "Template:Art Photo".to_string(),
+                "Template:Artwork".to_string(),
+            ],
+        );
+        wiki.paginate_templates = true;
+        let mock = MockApi::start(wiki);
+        let commons = mock_commons(&mock, &temp_file("templates_used.jsonl"));
+        let files: Vec<String> = vec!["Painting.jpg", "Photo.jpg", "Existing_file.jpg"]
+            .into_iter()
+            .map(|f| f.to_string())
+            .collect();
+        let used = commons
+            .templates_used(&files, &["Artwork", "Art Photo"])
+            .unwrap();
+        assert_eq!(used.len(), 3);
+        assert_eq!(used["Painting.jpg"].len(), 1);
+        assert!(used["Photo.jpg"].contains("Art Photo"));
+        assert!(used["Photo.jpg"].contains("Artwork"));
+        assert!(used["Existing_file.jpg"].is_empty());
+        let template_queries = |mock: &MockApi| {
+            mock.wiki
+                .lock()
+                .unwrap()
+                .requests_for("query")
+                .iter()
+                .filter(|r| r.get("prop").map(|p| p.as_str()) == Some("templates"))
+                .count()
+        };
+        // One response per page using any of them
+        assert_eq!(template_queries(&mock), 2);
+
+        // More templates than the API takes in one request
+        let mut many: Vec<String> = (0..60).map(|i| format!("Other {}", i)).collect();
+        many.push("Art Photo".to_string());
+        let many: Vec<&str> = many.iter().map(|t| t.as_str()).collect();
+        let used = commons.templates_used(&files, &many).unwrap();
+        assert!(used["Photo.jpg"].contains("Art Photo"));
+        assert_eq!(used["Photo.jpg"].len(), 1);
+        // The second chunk, one response per page
+        assert_eq!(template_queries(&mock), 4);
+    }
+
+    #[test]
+    fn exclusions_offline() {
+        let mut wiki = mock_wiki();
+        wiki.add_page("File:Kunst.jpg", 104);
+        wiki.add_page("File:Map.png", 105);
+        wiki.add_page("File:Scan.jpg", 106);
+        wiki.redirects.insert(
+            "Template:Kunstwerk".to_string(),
+            "Template:Artwork".to_string(),
+        );
+        wiki.templates.insert(
+            "File:Kunst.jpg".to_string(),
+            vec!["Template:Kunstwerk".to_string()],
+        );
+        wiki.categories.insert(
+            "File:Map.png".to_string(),
+            vec!["Category:Old maps".to_string()],
+        );
+        wiki.statements.insert(
+            "M106".to_string(),
+            vec![json!({
+                "mainsnak":{
+                    "snaktype":"value",
+                    "property":"P6243",
+                    "datavalue":{
+                        "value":{"entity-type":"item","numeric-id":5,"id":"Q5"},
+                        "type":"wikibase-entityid"
+                    }
+                },
+                "type":"statement",
+                "id":"M106$1",
+                "rank":"normal"
+            })],
+        );
+        let mock = MockApi::start(wiki);
+        let mut commons = mock_commons(&mock, &temp_file("exclusions.jsonl"));
+        commons.settings.exclude.categories = vec!["Old maps".to_string()];
+        let files: Vec<String> = vec![
+            "Painting.jpg",
+            "Kunst.jpg",
+            "Map.png",
+            "Scan.jpg",
+            "Existing_file.jpg",
+        ]
+        .into_iter()
+        .map(|f| f.to_string())
+        .collect();
+        commons.prefetch_files(&files).unwrap();
+        commons.prefetch_exclusions(&files).unwrap();
+
+        let artwork = Some(Exclusion::Template("Artwork".to_string()));
+        assert_eq!(commons.exclusion("Painting.jpg").unwrap(), artwork);
+        // Through a redirect to {{Artwork}}
+        assert_eq!(commons.exclusion("Kunst.jpg").unwrap(), artwork);
+        assert_eq!(
+            commons.exclusion("Map.png").unwrap(),
+            Some(Exclusion::Category("Old maps".to_string()))
+        );
+        assert_eq!(
+            commons.exclusion("Scan.jpg").unwrap(),
+            Some(Exclusion::Property("P6243".to_string()))
+        );
+        assert_eq!(commons.excluded("Existing_file.jpg").unwrap(), None);
+        assert_eq!(
+            commons.excluded("Map.png").unwrap(),
+            Some(Outcome::Excluded {
+                rule: "in category Old maps".to_string()
+            })
+        );
+
+        let wiki = mock.wiki.lock().unwrap();
+        let queries = |prop: &str| {
+            wiki.requests_for("query")
+                .iter()
+                .filter(|r| r.get("prop").map(|p| p.as_str()) == Some(prop))
+                .count()
+        };
+        assert_eq!(
+            (
+                queries("redirects"),
+                queries("templates"),
+                queries("categories")
+            ),
+            (1, 1, 1)
+        );
+        assert!(wiki.requests_for("wbgetclaims").is_empty());
+    }
+
+    #[test]
+    #[ignore] // Needs network access; run with `cargo test -- --ignored`
+    fn is_artwork() {
+        let commons = MW::new("https://commons.wikimedia.org/w/api.php").unwrap();
+        assert!(commons.is_artwork(
+            &"File:Lady_Elizabeth_Hamilton_(1753–1797),_Countess_of_Derby.jpg".to_string()
+        ));
+        assert!(!commons.is_artwork(
+            &"File:09797jfBarangays_West_Triangle_Quezon_City_Avenue_Bridgefvf_02.jpg".to_string()
+        ));
+    }
+}
@@ -0,0 +1,167 @@
+//! End-of-run summary: what happened to the candidates, and how fast
+
+use crate::bot_log::Outcome;
+use crate::error::Result;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::Instant;
+
+/// A P18 value on Wikidata that names a file page which is now a redirect
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StaleP18 {
+    pub item: String,
+    pub file: String,
+    /// The file the redirect leads to, which was edited instead
+    pub target: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RunReport {
+    /// Candidates returned by the source (SPARQL, PetScan)
+    pub candidates: usize,
+    /// Of those, left out since they are in the bot log
+    pub in_bot_log: usize,
+    /// Left out by the exclusion rules
+    pub excluded: usize,
+    pub already_present: usize,
+    pub page_missing: usize,
+    /// Statements added, or existing ones changed
+    pub edited: usize,
+    /// Edits sent to the API; with batching, one can cover several candidates
+    pub edits_sent: usize,
+    /// Error class => candidates that failed with it
+    pub failed: BTreeMap<String, usize>,
+    /// P18 values to fix on Wikidata
+    pub stale_p18: Vec<StaleP18>,
+    pub elapsed_seconds: f64,
+    pub edits_per_minute: f64,
+    #[serde(skip)]
+    started: Option<Instant>,
+}
+
+impl RunReport {
+    pub fn start() -> Self {
+        Self {
+            started: Some(Instant::now()),
+            ..Default::default()
+        }
+    }
+
+    /// Counts candidates from a source, `in_bot_log` of which are left out
+    pub fn add_candidates(&mut self, candidates: usize, in_bot_log: usize) {
+        self.candidates += candidates;
+        self.in_bot_log += in_bot_log;
+    }
+
+    pub fn add_outcome(&mut self, outcome: &Outcome) {
+        match outcome {
+            Outcome::Added { .. }
+            | Outcome::RankUpgraded { .. }
+            | Outcome::QualifiersMerged { .. } => self.edited += 1,
+            Outcome::AlreadyPresent => self.already_present += 1,
+            Outcome::Excluded { .. } => self.excluded += 1,
+            Outcome::PageMissing => self.page_missing += 1,
+            Outcome::ApiError { class, .. } => {
+                let class = if class.is_empty() { "unknown" } else { class };
+                *self.failed.entry(class.to_string()).or_insert(0) += 1
+            }
+            Outcome::Queued { .. } => {}
+        }
+    }
+
+    /// Records a file name that redirects, unless it is known already
+    pub fn add_stale_p18(&mut self, item: &str, file: &str, target: &str) {
+        let stale = StaleP18 {
+            item: item.to_string(),
+            file: file.to_string(),
+            target: target.to_string(),
+        };
+        if !self.stale_p18.contains(&stale) {
+            self.stale_p18.push(stale);
+        }
+    }
+
+    pub fn failed_total(&self) -> usize {
+        self.failed.values().sum()
+    }
+
+    /// Sets the elapsed time and the rate of edits sent
+    pub fn finish(&mut self) {
+        if let Some(started) = self.started {
+            self.elapsed_seconds = started.elapsed().as_secs_f64();
+        }
+        if self.elapsed_seconds > 0.0 {
+            self.edits_per_minute = self.edits_sent as f64 * 60.0 / self.elapsed_seconds;
+        }
+    }
+
+    pub fn write_json(&self, filename: &str) -> Result<()> {
+        std::fs::write(filename, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+}
+
+impl fmt::Display for RunReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Candidates:        {}", self.candidates)?;
+        writeln!(f, "  in bot log:      {}", self.in_bot_log)?;
+        writeln!(f, "Excluded:          {}", self.excluded)?;
+        writeln!(f, "Already present:   {}", self.already_present)?;
+        writeln!(f, "Page missing:      {}", self.page_missing)?;
+        writeln!(
+            f,
+            "Edited:            {} ({} edits)",
+            self.edited, self.edits_sent
+        )?;
+        writeln!(f, "Failed:            {}", self.failed_total())?;
+        for (class, num) in &self.failed {
+            writeln!(f, "  {}: {}", class, num)?;
+        }
+        writeln!(f, "Stale P18:         {}", self.stale_p18.len())?;
+        writeln!(
+            f,
+            "Elapsed:           {:.1} s ({:.1} edits/minute)",
+            self.elapsed_seconds, self.edits_per_minute
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn run_report() {
+        let mut report = RunReport::start();
+        report.add_candidates(6, 1);
+        report.add_outcome(&Outcome::Added { revid: Some(1) });
+        report.add_outcome(&Outcome::RankUpgraded { revid: None });
+        report.add_outcome(&Outcome::Excluded {
+            rule: "uses {{Artwork}}".to_string(),
+        });
+        report.add_outcome(&Outcome::ApiError {
+            class: "network".to_string(),
+            message: "Network error: timed out".to_string(),
+        });
+        report.add_outcome(&Outcome::ApiError {
+            class: String::new(),
+            message: "From an old bot log".to_string(),
+        });
+        report.add_stale_p18("Q1", "Old_name.jpg", "New_name.jpg");
+        report.add_stale_p18("Q1", "Old_name.jpg", "New_name.jpg");
+        report.finish();
+
+        assert_eq!(report.edited, 2);
+        assert_eq!(report.excluded, 1);
+        assert_eq!(report.stale_p18.len(), 1);
+        assert_eq!(report.failed_total(), 2);
+        let text = report.to_string();
+        assert!(text.contains("Edited:            2 (0 edits)\n"));
+        assert!(text.contains("  network: 1\n"));
+        let j = serde_json::to_value(&report).unwrap();
+        assert_eq!(j["candidates"], json!(6));
+        assert_eq!(j["failed"]["unknown"], json!(1));
+        assert_eq!(j["stale_p18"][0]["target"], json!("New_name.jpg"));
+        assert!(j["elapsed_seconds"].is_number());
+    }
+}
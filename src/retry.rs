@@ -0,0 +1,214 @@
+//! Retrying API requests that failed for reasons that go away by themselves
+
+use crate::error::{Error, Recovery, Result};
+use chrono::{DateTime, Utc};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::thread;
+use std::time::Duration;
+
+/// How often, and after how long, failed requests are tried again
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Tries per request, including the first one
+    pub max_attempts: u32,
+    /// Sent as `maxlag` with every request, in seconds; `None` to not send it
+    pub maxlag: Option<u64>,
+    /// Wait before the second try; doubled for every further one
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+/// An API response, and the wait its `Retry-After` header asks for
+#[derive(Debug, Clone)]
+pub struct Response {
+    pub json: Value,
+    pub retry_after: Option<Duration>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            maxlag: Some(5),
+            initial_backoff: Duration::from_secs(2),
+            max_backoff: Duration::from_secs(120),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Exponential backoff for the given (zero-based) failed attempt
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt);
+        self.initial_backoff
+            .checked_mul(factor)
+            .unwrap_or(self.max_backoff)
+            .min(self.max_backoff)
+    }
+
+    /// How long to wait before trying again after `error`, at most `max_backoff`,
+    /// or `None` to give up. `retry_after` is what the response asked for.
+    /// `idempotent` is false for edits, which are not repeated after a network
+    /// or server error since they might have gone through.
+    pub fn delay(
+        &self,
+        error: &Error,
+        attempt: u32,
+        retry_after: Option<Duration>,
+        idempotent: bool,
+    ) -> Option<Duration> {
+        if attempt + 1 >= self.max_attempts || error.recovery() != Recovery::Retry {
+            return None;
+        }
+        let wait = self
+            .backoff(attempt)
+            .max(retry_after.unwrap_or_default())
+            .min(self.max_backoff);
+        match (error, error.api_code()) {
+            // A new token is fetched for the next try anyway
+            (_, Some("badtoken")) => Some(Duration::from_secs(0)),
+            (_, Some("maxlag")) => Some(
+                retry_after
+                    .unwrap_or_else(|| self.backoff(attempt))
+                    .min(self.max_backoff),
+            ),
+            (_, Some(_)) => Some(wait),
+            // Turned away before it was carried out
+            (Error::Http { status: 429, .. }, None) => Some(wait),
+            (_, None) if idempotent => Some(wait),
+            (_, None) => None,
+        }
+    }
+
+    /// Adds `maxlag` to request parameters
+    pub fn add_maxlag(&self, params: &mut HashMap<String, String>) {
+        if let Some(maxlag) = self.maxlag {
+            params.insert("maxlag".to_string(), maxlag.to_string());
+        }
+    }
+
+    /// Runs `request` until it returns a response without an error, or the error
+    /// is not worth another try. `request` gets the error of the previous try.
+    pub fn run<F>(&self, idempotent: bool, mut request: F) -> Result<Value>
+    where
+        F: FnMut(Option<&Error>) -> Result<Response>,
+    {
+        let mut previous: Option<Error> = None;
+        let mut attempt = 0;
+        loop {
+            let (error, retry_after) = match request(previous.as_ref()) {
+                Ok(response) => match Error::from_api_response(&response.json) {
+                    Some(e) => (e, response.retry_after.or_else(|| lag(&response.json))),
+                    None => return Ok(response.json),
+                },
+                Err(e) => {
+                    let retry_after = match e {
+                        Error::Http { retry_after, .. } => retry_after,
+                        _ => None,
+                    };
+                    (e, retry_after)
+                }
+            };
+            match self.delay(&error, attempt, retry_after, idempotent) {
+                Some(delay) => {
+                    info!(class = %error.class(), ?delay, "{}; trying again", &error);
+                    thread::sleep(delay);
+                    previous = Some(error);
+                    attempt += 1;
+                }
+                None => return Err(error),
+            }
+        }
+    }
+}
+
+/// A `Retry-After` header value: seconds, or an HTTP date
+pub fn parse_retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    // A date in the past means right away
+    Some(
+        (date.with_timezone(&Utc) - Utc::now())
+            .to_std()
+            .unwrap_or_default(),
+    )
+}
+
+/// The replication lag in a `maxlag` error, for responses that came without
+/// a `Retry-After` header
+fn lag(j: &Value) -> Option<Duration> {
+    let lag = j["error"]["lag"].as_f64()?;
+    if lag < 0.0 {
+        return None;
+    }
+    Some(Duration::from_millis((lag * 1000.0) as u64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retry_delays() {
+        let policy = RetryPolicy::default();
+        assert_eq!(policy.backoff(0), Duration::from_secs(2));
+        assert_eq!(policy.backoff(2), Duration::from_secs(8));
+        assert_eq!(policy.backoff(30), Duration::from_secs(120));
+
+        let maxlag = json!({"error":{"code":"maxlag","info":"Waiting for db1","lag":7}});
+        let error = Error::from_api_response(&maxlag).unwrap();
+        assert_eq!(
+            policy.delay(&error, 0, lag(&maxlag), false),
+            Some(Duration::from_secs(7))
+        );
+        assert_eq!(policy.delay(&error, 4, lag(&maxlag), false), None);
+        let lagged = json!({"error":{"code":"maxlag","info":"Waiting for db1","lag":600}});
+        assert_eq!(
+            policy.delay(&error, 0, lag(&lagged), false),
+            Some(Duration::from_secs(120))
+        );
+
+        let ratelimited = Error::from_api_response(&json!({"error":{"code":"ratelimited"}}));
+        assert_eq!(
+            policy.delay(&ratelimited.unwrap(), 1, None, false),
+            Some(Duration::from_secs(4))
+        );
+        let network = Error::Network("502 Bad Gateway".to_string());
+        assert_eq!(
+            policy.delay(&network, 0, None, true),
+            Some(Duration::from_secs(2))
+        );
+        assert_eq!(policy.delay(&network, 0, None, false), None);
+
+        let too_many = Error::Http {
+            status: 429,
+            retry_after: Some(Duration::from_secs(30)),
+        };
+        assert_eq!(
+            policy.delay(&too_many, 0, Some(Duration::from_secs(30)), false),
+            Some(Duration::from_secs(30))
+        );
+        let unavailable = Error::Http {
+            status: 503,
+            retry_after: None,
+        };
+        assert_eq!(
+            policy.delay(&unavailable, 1, None, true),
+            Some(Duration::from_secs(4))
+        );
+        assert_eq!(policy.delay(&unavailable, 0, None, false), None);
+
+        assert_eq!(parse_retry_after("5"), Some(Duration::from_secs(5)));
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"),
+            Some(Duration::from_secs(0))
+        );
+        assert_eq!(parse_retry_after("soon"), None);
+        let missing = Error::MissingPage("File:X.jpg".to_string());
+        assert_eq!(policy.delay(&missing, 0, None, true), None);
+    }
+}
@@ -0,0 +1,238 @@
+//! Checkpoints of a depicts run, so an interrupted run can be resumed
+//!
+//! The run state is a JSON Lines file like the bot log: a `start` line with the
+//! job and its candidates, then `position` and `outcome` lines as the run goes on.
+
+use crate::bot_log::{lossy_lines, BotLog, BotLogEntry, Outcome};
+use crate::error::{Error, Result};
+use crate::jobs::DepictsJob;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::prelude::*;
+use std::io::BufReader;
+
+/// An (item, file) pair a run is going to deal with
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Candidate {
+    pub item: String,
+    pub file: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub outcome: Option<Outcome>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum RunStateLine {
+    Start {
+        job: DepictsJob,
+        candidates: Vec<Candidate>,
+    },
+    /// Index of the candidate being worked on
+    Position {
+        position: usize,
+    },
+    Outcome {
+        index: usize,
+        outcome: Outcome,
+    },
+    Finished,
+}
+
+#[derive(Debug, Clone)]
+pub struct RunState {
+    filename: String,
+    pub job: DepictsJob,
+    pub candidates: Vec<Candidate>,
+    pub position: usize,
+    pub finished: bool,
+    /// (item, normalized file) => index in `candidates`
+    index: HashMap<(String, String), usize>,
+}
+
+impl RunState {
+    /// Starts a new run state file, replacing any old one
+    pub fn create(
+        filename: &str,
+        job: &DepictsJob,
+        candidates: &[(String, String)],
+    ) -> Result<Self> {
+        let candidates: Vec<Candidate> = candidates
+            .iter()
+            .map(|(item, file)| Candidate {
+                item: item.to_string(),
+                file: file.to_string(),
+                outcome: None,
+            })
+            .collect();
+        File::create(filename)?;
+        let ret = Self::new(filename, job.to_owned(), candidates);
+        ret.append(&RunStateLine::Start {
+            job: ret.job.clone(),
+            candidates: ret.candidates.clone(),
+        })?;
+        Ok(ret)
+    }
+
+    pub fn load(filename: &str) -> Result<Self> {
+        let f = File::open(filename)?;
+        let mut ret: Option<Self> = None;
+        for line in lossy_lines(BufReader::new(f)) {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            // Most likely the last line, torn by a crash; resume without it
+            let line: RunStateLine = match serde_json::from_str(&line) {
+                Ok(line) => line,
+                Err(e) => {
+                    warn!("Skipping unparsable line in {}: {}", filename, e);
+                    continue;
+                }
+            };
+            match line {
+                RunStateLine::Start { job, candidates } => {
+                    ret = Some(Self::new(filename, job, candidates))
+                }
+                line => ret
+                    .as_mut()
+                    .ok_or_else(|| Error::Parse(format!("{}: No start line", filename)))?
+                    .apply(line),
+            }
+        }
+        ret.ok_or_else(|| Error::Parse(format!("{}: Empty run state", filename)))
+    }
+
+    fn apply(&mut self, line: RunStateLine) {
+        match line {
+            RunStateLine::Start { .. } => {}
+            RunStateLine::Position { position } => self.position = position,
+            RunStateLine::Outcome { index, outcome } => {
+                if let Some(candidate) = self.candidates.get_mut(index) {
+                    candidate.outcome = Some(outcome);
+                }
+            }
+            RunStateLine::Finished => self.finished = true,
+        }
+    }
+
+    fn new(filename: &str, job: DepictsJob, candidates: Vec<Candidate>) -> Self {
+        let index = candidates
+            .iter()
+            .enumerate()
+            .map(|(num, c)| ((c.item.to_owned(), BotLog::normalize_file(&c.file)), num))
+            .collect();
+        Self {
+            filename: filename.to_string(),
+            job,
+            candidates,
+            position: 0,
+            finished: false,
+            index,
+        }
+    }
+
+    pub fn filename(&self) -> &str {
+        &self.filename
+    }
+
+    /// Indices of the candidates without an outcome, or with one worth another
+    /// try, as in the bot log
+    pub fn pending(&self) -> Vec<usize> {
+        self.candidates
+            .iter()
+            .enumerate()
+            .filter(|(_, c)| c.outcome.as_ref().map_or(true, |o| !o.is_final()))
+            .map(|(num, _)| num)
+            .collect()
+    }
+
+    pub fn set_position(&mut self, position: usize) -> Result<()> {
+        self.position = position;
+        self.append(&RunStateLine::Position { position })
+    }
+
+    /// Records the outcome of a bot log entry, if it belongs to this run
+    pub fn record(&mut self, entry: &BotLogEntry) -> Result<()> {
+        let outcome = match &entry.outcome {
+            Some(outcome) => outcome.to_owned(),
+            None => return Ok(()),
+        };
+        let index = match self
+            .index
+            .get(&(entry.item.to_owned(), entry.file.to_owned()))
+        {
+            Some(index) => *index,
+            None => return Ok(()),
+        };
+        self.candidates[index].outcome = Some(outcome.clone());
+        self.append(&RunStateLine::Outcome { index, outcome })
+    }
+
+    pub fn finish(&mut self) -> Result<()> {
+        self.finished = true;
+        self.append(&RunStateLine::Finished)
+    }
+
+    fn append(&self, line: &RunStateLine) -> Result<()> {
+        let mut file = OpenOptions::new().append(true).open(&self.filename)?;
+        let mut line = serde_json::to_string(line)?;
+        line.push('\n');
+        file.write_all(line.as_bytes())?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_api::temp_file;
+
+    #[test]
+    fn run_state_round_trip() {
+        let filename = temp_file("run_state_round_trip.jsonl");
+        let job = DepictsJob::new("SELECT ?q ?image {}", "test");
+        let candidates = vec![
+            ("Q1".to_string(), "A b.jpg".to_string()),
+            ("Q2".to_string(), "C.jpg".to_string()),
+            ("Q3".to_string(), "D.jpg".to_string()),
+        ];
+        let mut state = RunState::create(&filename, &job, &candidates).unwrap();
+        state.set_position(1).unwrap();
+        let entry = BotLogEntry::new("Q1", "A b.jpg", "P180", Some("test"))
+            .with_outcome(Outcome::Added { revid: Some(7) });
+        state.record(&entry).unwrap();
+        // Failed, so tried again
+        state
+            .record(
+                &BotLogEntry::new("Q2", "C.jpg", "P180", Some("test")).with_outcome(
+                    Outcome::ApiError {
+                        class: "network".to_string(),
+                        message: "Network error: timed out".to_string(),
+                    },
+                ),
+            )
+            .unwrap();
+        // Not part of this run
+        state
+            .record(
+                &BotLogEntry::new("Q9", "A b.jpg", "P180", None)
+                    .with_outcome(Outcome::AlreadyPresent),
+            )
+            .unwrap();
+        // Torn lines, one inside a multi-byte character
+        let mut file = OpenOptions::new().append(true).open(&filename).unwrap();
+        file.write_all(b"{\"type\":\"outcome\",\"file\":\"(1753\xe2\x80\n")
+            .unwrap();
+        file.write_all(br#"{"type":"posi"#).unwrap();
+
+        let state = RunState::load(&filename).unwrap();
+        assert_eq!(state.job.description, "test");
+        assert_eq!(state.position, 1);
+        assert_eq!(state.pending(), vec![1, 2]);
+        assert_eq!(
+            state.candidates[0].outcome,
+            Some(Outcome::Added { revid: Some(7) })
+        );
+        assert!(!state.finished);
+    }
+}
@@ -0,0 +1,121 @@
+//! Layered configuration: ini file, then environment variables, then command line
+//!
+//! Environment variables are named after the setting, with sections separated
+//! by `__`, e.g. `COMMONS_STATEMENTS_API__COMMONS` for `commons` in `[api]`.
+
+use crate::error::Result;
+use crate::exclusions::ExclusionConfig;
+use crate::throttle::ThrottleConfig;
+
+pub const ENV_PREFIX: &str = "COMMONS_STATEMENTS";
+
+/// The `[api]` section
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct ApiSettings {
+    pub commons: String,
+    pub wikidata: String,
+    /// SPARQL endpoint; by default the one Wikidata announces
+    pub sparql: Option<String>,
+    pub petscan: String,
+    pub user_agent: String,
+}
+
+impl Default for ApiSettings {
+    fn default() -> Self {
+        Self {
+            commons: "https://commons.wikimedia.org/w/api.php".to_string(),
+            wikidata: "https://www.wikidata.org/w/api.php".to_string(),
+            sparql: None,
+            petscan: "https://petscan.wmflabs.org/".to_string(),
+            user_agent: format!("commons_statements/{}", env!("CARGO_PKG_VERSION")),
+        }
+    }
+}
+
+/// The `[bot]` section
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct BotSettings {
+    /// The JSON Lines bot log
+    pub log: String,
+    /// Appended to every edit summary
+    pub summary_tag: String,
+    /// The old free-form bot log, read into the index on startup; empty to skip
+    pub legacy_log: String,
+}
+
+impl Default for BotSettings {
+    fn default() -> Self {
+        Self {
+            log: "bot_log.jsonl".to_string(),
+            summary_tag: "#rust_commons_statement".to_string(),
+            legacy_log: "bot.log".to_string(),
+        }
+    }
+}
+
+/// Everything but the credentials, which come from the same layers
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    pub api: ApiSettings,
+    pub bot: BotSettings,
+    pub throttle: ThrottleConfig,
+    pub exclude: ExclusionConfig,
+}
+
+impl Settings {
+    pub fn from_config(config: &config::Config) -> Result<Self> {
+        Ok(config.clone().try_into()?)
+    }
+}
+
+/// Reads `filename`, if it exists, then `COMMONS_STATEMENTS_*` environment
+/// variables, then `overrides` as ("section.key", value) pairs; later layers win
+pub fn layered_config(filename: &str, overrides: &[(String, String)]) -> Result<config::Config> {
+    let mut config = config::Config::default();
+    config.merge(config::File::with_name(filename).required(false))?;
+    config.merge(config::Environment::with_prefix(ENV_PREFIX).separator("__"))?;
+    for (key, value) in overrides {
+        config.set(key, value.as_str())?;
+    }
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_api::temp_file;
+
+    #[test]
+    fn settings_layers() {
+        let ini = temp_file("settings_layers.ini");
+        std::fs::write(
+            &ini,
+            "[api]\ncommons = https://test.commons.wikimedia.org/w/api.php\nsparql = http://localhost/sparql\n[bot]\nsummary_tag = from_file\n[throttle]\nedit_delay_ms = 1000\n",
+        )
+        .unwrap();
+        std::env::set_var("COMMONS_STATEMENTS_BOT__SUMMARY_TAG", "#from_env");
+        let overrides = vec![("api.sparql".to_string(), "http://mirror/sparql".to_string())];
+        let settings = Settings::from_config(&layered_config(&ini, &overrides).unwrap()).unwrap();
+        std::env::remove_var("COMMONS_STATEMENTS_BOT__SUMMARY_TAG");
+
+        assert_eq!(
+            settings.api.commons,
+            "https://test.commons.wikimedia.org/w/api.php"
+        );
+        assert_eq!(settings.api.wikidata, ApiSettings::default().wikidata);
+        assert_eq!(
+            settings.api.sparql,
+            Some("http://mirror/sparql".to_string())
+        );
+        assert_eq!(settings.bot.summary_tag, "#from_env");
+        assert_eq!(settings.bot.log, "bot_log.jsonl");
+        assert_eq!(settings.throttle.edit_delay_ms, Some(1000));
+
+        // Everything can come from the environment
+        let config = layered_config(&temp_file("missing.ini"), &[]).unwrap();
+        assert_eq!(Settings::from_config(&config).unwrap(), Settings::default());
+    }
+}
@@ -0,0 +1,446 @@
+//! Building new statements as Wikibase JSON, and comparing them to existing ones
+
+use serde_json::Value;
+use wikibase::SnakType;
+
+const WIKIDATA_ENTITY_PREFIX: &str = "http://www.wikidata.org/entity/";
+
+/// A snak to be sent as main snak, qualifier or reference of a new claim.
+/// The JSON datavalue type follows from the value given to the constructors.
+#[derive(Debug, Clone)]
+pub struct NewSnak {
+    pub property: String,
+    pub snaktype: SnakType,
+    /// `{"value":...,"type":...}`; `None` for "novalue"/"somevalue" snaks
+    pub datavalue: Option<Value>,
+}
+
+impl NewSnak {
+    pub fn new(
+        property: &str,
+        snaktype: SnakType,
+        valuetype: &str,
+        value: &wikibase::Value,
+    ) -> Self {
+        let datavalue = match snaktype {
+            SnakType::Value => Some(json!({"value":value,"type":valuetype})),
+            _ => None,
+        };
+        Self {
+            property: property.to_string(),
+            snaktype,
+            datavalue,
+        }
+    }
+
+    /// A value snak; the datavalue type is derived from the value
+    pub fn value(property: &str, value: wikibase::Value) -> Self {
+        let valuetype = Self::datavalue_type(&value);
+        Self::new(property, SnakType::Value, valuetype, &value)
+    }
+
+    /// An "unknown value" snak
+    pub fn some_value(property: &str) -> Self {
+        Self {
+            property: property.to_string(),
+            snaktype: SnakType::UnknownValue,
+            datavalue: None,
+        }
+    }
+
+    /// A "no value" snak
+    pub fn no_value(property: &str) -> Self {
+        Self {
+            property: property.to_string(),
+            snaktype: SnakType::NoValue,
+            datavalue: None,
+        }
+    }
+
+    /// Item, property, lexeme etc. ID
+    pub fn entity(property: &str, entity_id: &str) -> Self {
+        let entity_type = match entity_id.chars().next() {
+            Some('P') => "property",
+            Some('L') => "lexeme",
+            Some('M') => "mediainfo",
+            _ => "item",
+        };
+        Self::with_datavalue(
+            property,
+            "wikibase-entityid",
+            json!({"entity-type":entity_type,"id":entity_id}),
+        )
+    }
+
+    /// For string, external ID, commonsMedia and URL properties
+    pub fn string(property: &str, s: &str) -> Self {
+        Self::with_datavalue(property, "string", json!(s))
+    }
+
+    pub fn monolingual_text(property: &str, text: &str, language: &str) -> Self {
+        Self::with_datavalue(
+            property,
+            "monolingualtext",
+            json!({"text":text,"language":language}),
+        )
+    }
+
+    /// `time` in Wikibase format, e.g. "+2019-05-01T00:00:00Z"; precision 11 is day, 9 is year
+    pub fn time(property: &str, time: &str, precision: u64) -> Self {
+        Self::with_datavalue(
+            property,
+            "time",
+            json!({
+                "time":time,
+                "timezone":0,
+                "before":0,
+                "after":0,
+                "precision":precision,
+                "calendarmodel":format!("{}Q1985727", WIKIDATA_ENTITY_PREFIX)
+            }),
+        )
+    }
+
+    /// `amount` with sign, e.g. "+12.5"; `unit` is an item ID, or `None` for no unit
+    pub fn quantity(property: &str, amount: &str, unit: Option<&str>) -> Self {
+        let unit = match unit {
+            Some(q) => format!("{}{}", WIKIDATA_ENTITY_PREFIX, q),
+            None => "1".to_string(),
+        };
+        Self::with_datavalue(property, "quantity", json!({"amount":amount,"unit":unit}))
+    }
+
+    /// Coordinates on Earth
+    pub fn globe_coordinate(property: &str, latitude: f64, longitude: f64, precision: f64) -> Self {
+        Self::with_datavalue(
+            property,
+            "globecoordinate",
+            json!({
+                "latitude":latitude,
+                "longitude":longitude,
+                "altitude":null,
+                "precision":precision,
+                "globe":format!("{}Q2", WIKIDATA_ENTITY_PREFIX)
+            }),
+        )
+    }
+
+    fn with_datavalue(property: &str, valuetype: &str, value: Value) -> Self {
+        Self {
+            property: property.to_string(),
+            snaktype: SnakType::Value,
+            datavalue: Some(json!({"value":value,"type":valuetype})),
+        }
+    }
+
+    /// Whether a snak in the JSON of an existing statement has the same property and value
+    pub fn matches(&self, snak: &Value) -> bool {
+        if snak["property"].as_str() != Some(self.property.as_str())
+            || snak["snaktype"] != json!(self.snaktype)
+        {
+            return false;
+        }
+        match (&self.datavalue, snak.get("datavalue")) {
+            (Some(a), Some(b)) => Self::same_datavalue(a, b),
+            (None, None) => true,
+            _ => false,
+        }
+    }
+
+    /// Compares the parts of two datavalues that identify the value, as the API
+    /// returns more fields (numeric IDs, bounds) than we send
+    fn same_datavalue(a: &Value, b: &Value) -> bool {
+        if a["type"] != b["type"] {
+            return false;
+        }
+        let (a, b) = (&a["value"], &b["value"]);
+        let keys: &[&str] = match b.as_object() {
+            Some(_) => match a.get("id") {
+                Some(_) => &["id"],
+                None => &[
+                    "text",
+                    "language",
+                    "time",
+                    "precision",
+                    "amount",
+                    "unit",
+                    "latitude",
+                    "longitude",
+                ],
+            },
+            None => return a == b,
+        };
+        keys.iter().all(|k| a.get(*k) == b.get(*k))
+    }
+
+    pub fn datavalue_type(value: &wikibase::Value) -> &'static str {
+        match value {
+            wikibase::Value::Coordinate(_) => "globecoordinate",
+            wikibase::Value::MonoLingual(_) => "monolingualtext",
+            wikibase::Value::Entity(_) => "wikibase-entityid",
+            wikibase::Value::Quantity(_) => "quantity",
+            wikibase::Value::StringValue(_) => "string",
+            wikibase::Value::Time(_) => "time",
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        let mut ret = json!({"snaktype":self.snaktype,"property":self.property});
+        if let Some(datavalue) = &self.datavalue {
+            ret["datavalue"] = datavalue.clone();
+        }
+        ret
+    }
+
+    /// Groups snaks by property, as used for qualifiers and reference snaks.
+    /// Returns the snaks object and the property order.
+    fn group_by_property(snaks: &[NewSnak]) -> (Value, Vec<String>) {
+        let mut order: Vec<String> = vec![];
+        let mut grouped = serde_json::Map::new();
+        snaks.iter().for_each(|snak| {
+            if !order.contains(&snak.property) {
+                order.push(snak.property.clone());
+                grouped.insert(snak.property.clone(), json!([]));
+            }
+            if let Some(Value::Array(a)) = grouped.get_mut(&snak.property) {
+                a.push(snak.to_json());
+            }
+        });
+        (Value::Object(grouped), order)
+    }
+}
+
+/// How `ensure_statement` treats an existing statement with the same property and value
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DuplicatePolicy {
+    /// Leave the existing statement alone, whatever its rank
+    #[default]
+    IgnoreRank,
+    /// Raise a normal rank to the requested rank; deprecated statements are left alone
+    UpgradeRank,
+    /// Add qualifiers the existing statement does not have yet
+    MergeQualifiers,
+}
+
+/// Statement rank, in ascending order
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Rank {
+    Deprecated,
+    #[default]
+    Normal,
+    Preferred,
+}
+
+/// Raises the rank of statement JSON to `rank`. Returns false if there is nothing to do.
+pub(crate) fn upgrade_rank(statement: &mut Value, rank: Rank) -> bool {
+    let current: Rank = serde_json::from_value(statement["rank"].clone()).unwrap_or_default();
+    if current == Rank::Deprecated || current >= rank {
+        return false;
+    }
+    statement["rank"] = json!(rank);
+    true
+}
+
+/// Adds qualifiers missing from statement JSON. Returns false if there is nothing to do.
+pub(crate) fn merge_qualifiers(statement: &mut Value, qualifiers: &[NewSnak]) -> bool {
+    let missing: Vec<&NewSnak> = qualifiers
+        .iter()
+        .filter(|q| {
+            !statement["qualifiers"][&q.property]
+                .as_array()
+                .map_or(false, |a| a.iter().any(|existing| q.matches(existing)))
+        })
+        .collect();
+    if missing.is_empty() {
+        return false;
+    }
+    if !statement["qualifiers"].is_object() {
+        statement["qualifiers"] = json!({});
+    }
+    if !statement["qualifiers-order"].is_array() {
+        statement["qualifiers-order"] = json!([]);
+    }
+    missing.iter().for_each(|q| {
+        if !statement["qualifiers"][&q.property].is_array() {
+            statement["qualifiers"][&q.property] = json!([]);
+        }
+        if let Some(a) = statement["qualifiers"][&q.property].as_array_mut() {
+            a.push(q.to_json());
+        }
+        let property = json!(q.property);
+        if let Some(order) = statement["qualifiers-order"].as_array_mut() {
+            if !order.contains(&property) {
+                order.push(property);
+            }
+        }
+    });
+    true
+}
+
+/// A statement to be created, with optional qualifiers and references
+#[derive(Debug, Clone)]
+pub struct NewClaim {
+    pub main_snak: NewSnak,
+    pub rank: Rank,
+    pub qualifiers: Vec<NewSnak>,
+    /// Each reference is a list of snaks
+    pub references: Vec<Vec<NewSnak>>,
+}
+
+impl NewClaim {
+    pub fn new(main_snak: NewSnak) -> Self {
+        Self {
+            main_snak,
+            rank: Rank::Normal,
+            qualifiers: vec![],
+            references: vec![],
+        }
+    }
+
+    pub fn with_rank(mut self, rank: Rank) -> Self {
+        self.rank = rank;
+        self
+    }
+
+    pub fn with_qualifier(mut self, snak: NewSnak) -> Self {
+        self.qualifiers.push(snak);
+        self
+    }
+
+    pub fn with_reference(mut self, snaks: Vec<NewSnak>) -> Self {
+        self.references.push(snaks);
+        self
+    }
+
+    pub fn to_json(&self) -> Value {
+        let mut ret =
+            json!({"mainsnak":self.main_snak.to_json(),"type":"statement","rank":self.rank});
+        if !self.qualifiers.is_empty() {
+            let (qualifiers, order) = NewSnak::group_by_property(&self.qualifiers);
+            ret["qualifiers"] = qualifiers;
+            ret["qualifiers-order"] = json!(order);
+        }
+        if !self.references.is_empty() {
+            let references: Vec<Value> = self
+                .references
+                .iter()
+                .map(|snaks| {
+                    let (snaks, order) = NewSnak::group_by_property(snaks);
+                    json!({"snaks":snaks,"snaks-order":order})
+                })
+                .collect();
+            ret["references"] = json!(references);
+        }
+        ret
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wikibase::{EntityType, EntityValue};
+
+    #[test]
+    fn claim_with_qualifiers_and_references() {
+        let claim = NewClaim::new(NewSnak::value(
+            "P180",
+            wikibase::Value::Entity(EntityValue::new(EntityType::Item, "Q42".to_string())),
+        ))
+        .with_rank(Rank::Preferred)
+        .with_qualifier(NewSnak::entity("P462", "Q1088"))
+        .with_qualifier(NewSnak::no_value("P518"))
+        .with_qualifier(NewSnak::entity("P462", "Q39338"))
+        .with_reference(vec![
+            NewSnak::some_value("P887"),
+            NewSnak::time("P813", "+2019-05-01T00:00:00Z", 11),
+        ]);
+
+        let j = claim.to_json();
+        assert_eq!(j["rank"], json!("preferred"));
+        assert_eq!(j["mainsnak"]["property"], json!("P180"));
+        assert_eq!(
+            j["mainsnak"]["datavalue"]["type"],
+            json!("wikibase-entityid")
+        );
+        assert_eq!(j["qualifiers-order"], json!(["P462", "P518"]));
+        assert_eq!(j["qualifiers"]["P462"].as_array().unwrap().len(), 2);
+        assert!(j["qualifiers"]["P518"][0].get("datavalue").is_none());
+        assert_eq!(j["references"][0]["snaks-order"], json!(["P887", "P813"]));
+        assert!(j["references"][0]["snaks"]["P887"][0]
+            .get("datavalue")
+            .is_none());
+        assert_eq!(
+            j["references"][0]["snaks"]["P813"][0]["datavalue"]["value"]["precision"],
+            json!(11)
+        );
+    }
+
+    #[test]
+    fn snak_datavalue_types() {
+        let types: Vec<Value> = vec![
+            NewSnak::value("P1", wikibase::Value::StringValue("x".to_string())),
+            NewSnak::entity("P180", "Q42"),
+            NewSnak::string("P1259", "Foo.jpg"),
+            NewSnak::monolingual_text("P1476", "Titel", "de"),
+            NewSnak::time("P571", "+1890-00-00T00:00:00Z", 9),
+            NewSnak::quantity("P2048", "+12", Some("Q11573")),
+            NewSnak::globe_coordinate("P1259", 52.5, 13.4, 0.0001),
+        ]
+        .iter()
+        .map(|snak| snak.to_json()["datavalue"]["type"].clone())
+        .collect();
+        assert_eq!(
+            types,
+            vec![
+                json!("string"),
+                json!("wikibase-entityid"),
+                json!("string"),
+                json!("monolingualtext"),
+                json!("time"),
+                json!("quantity"),
+                json!("globecoordinate")
+            ]
+        );
+        assert_eq!(
+            NewSnak::entity("P170", "Q5582").to_json()["datavalue"]["value"]["entity-type"],
+            json!("item")
+        );
+    }
+
+    #[test]
+    fn existing_statement_matching() {
+        let existing = json!({
+            "mainsnak":{
+                "snaktype":"value",
+                "property":"P180",
+                "datavalue":{
+                    "value":{"entity-type":"item","numeric-id":42,"id":"Q42"},
+                    "type":"wikibase-entityid"
+                }
+            },
+            "type":"statement",
+            "id":"M123$5627445f-43cb-ed6d-3adb-760e85bd17ee",
+            "rank":"normal"
+        });
+        assert!(NewSnak::entity("P180", "Q42").matches(&existing["mainsnak"]));
+        assert!(!NewSnak::entity("P180", "Q4").matches(&existing["mainsnak"]));
+        assert!(!NewSnak::entity("P170", "Q42").matches(&existing["mainsnak"]));
+        assert!(!NewSnak::some_value("P180").matches(&existing["mainsnak"]));
+
+        let mut statement = existing.clone();
+        assert!(!upgrade_rank(&mut statement, Rank::Normal));
+        assert!(upgrade_rank(&mut statement, Rank::Preferred));
+        assert_eq!(statement["rank"], json!("preferred"));
+        statement["rank"] = json!("deprecated");
+        assert!(!upgrade_rank(&mut statement, Rank::Preferred));
+
+        let mut statement = existing;
+        let qualifiers = vec![NewSnak::entity("P462", "Q1088")];
+        assert!(merge_qualifiers(&mut statement, &qualifiers));
+        assert_eq!(statement["qualifiers-order"], json!(["P462"]));
+        assert!(!merge_qualifiers(&mut statement, &qualifiers));
+        assert_eq!(statement["qualifiers"]["P462"].as_array().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,189 @@
+//! Keeps the edit rate within the limits of the Commons bot policy
+
+use chrono::{Timelike, Utc};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The `[throttle]` section of the ini file
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct ThrottleConfig {
+    /// Sustained edit rate; 0 for no limit
+    pub edits_per_minute: u32,
+    /// Edits that may be sent back to back before the rate applies
+    pub burst: u32,
+    /// No edits from this hour (UTC) on...
+    pub quiet_from: Option<u32>,
+    /// ...until this hour (UTC); the range may wrap around midnight
+    pub quiet_until: Option<u32>,
+    /// A fixed pause between edits instead of `edits_per_minute` and `burst`
+    pub edit_delay_ms: Option<u64>,
+}
+
+impl Default for ThrottleConfig {
+    fn default() -> Self {
+        Self {
+            edits_per_minute: 120,
+            burst: 1,
+            quiet_from: None,
+            quiet_until: None,
+            edit_delay_ms: None,
+        }
+    }
+}
+
+impl ThrottleConfig {
+    pub fn is_quiet_hour(&self, hour: u32) -> bool {
+        match (self.quiet_from, self.quiet_until) {
+            (Some(from), Some(until)) if from <= until => hour >= from && hour < until,
+            (Some(from), Some(until)) => hour >= from || hour < until,
+            _ => false,
+        }
+    }
+}
+
+/// Slowest rate lag adaptation goes down to, as a share of the configured one
+const MIN_RATE_FACTOR: f64 = 0.125;
+
+/// A token bucket for edits
+#[derive(Debug, Clone)]
+pub struct Throttle {
+    pub config: ThrottleConfig,
+    /// Edits that can be sent right now; negative if the next one has to wait
+    tokens: f64,
+    last: Option<Instant>,
+    /// Share of the configured rate in use; lowered while the servers lag
+    rate_factor: f64,
+}
+
+impl Throttle {
+    pub fn new(mut config: ThrottleConfig) -> Self {
+        if let Some(delay) = config.edit_delay_ms {
+            config.edits_per_minute = (60_000 / delay.max(1)).max(1) as u32;
+            config.burst = 1;
+        }
+        Self {
+            tokens: config.burst.max(1) as f64,
+            config,
+            last: None,
+            rate_factor: 1.0,
+        }
+    }
+
+    /// No limits at all
+    pub fn unlimited() -> Self {
+        Self::new(ThrottleConfig {
+            edits_per_minute: 0,
+            ..Default::default()
+        })
+    }
+
+    /// Blocks until the next edit may be sent
+    pub fn wait(&mut self) {
+        loop {
+            let now = Utc::now();
+            if !self.config.is_quiet_hour(now.hour()) {
+                break;
+            }
+            let rest_of_hour = 3600 - u64::from(now.minute() * 60 + now.second());
+            info!(seconds = rest_of_hour, "Quiet hours; pausing");
+            thread::sleep(Duration::from_secs(rest_of_hour));
+        }
+        let delay = self.reserve(Instant::now());
+        if delay > Duration::from_secs(0) {
+            thread::sleep(delay);
+        }
+    }
+
+    /// Takes an edit from the bucket; returns how long to wait before sending it
+    pub fn reserve(&mut self, now: Instant) -> Duration {
+        if self.config.edits_per_minute == 0 {
+            return Duration::from_secs(0);
+        }
+        let rate = self.edits_per_second();
+        if let Some(last) = self.last {
+            let elapsed = now.saturating_duration_since(last).as_secs_f64();
+            self.tokens = (self.tokens + elapsed * rate).min(self.config.burst.max(1) as f64);
+        }
+        self.last = Some(now);
+        self.tokens -= 1.0;
+        if self.tokens >= 0.0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_secs_f64(-self.tokens / rate)
+        }
+    }
+
+    /// Halves the rate; for responses reporting server lag
+    pub fn slow_down(&mut self) {
+        self.rate_factor = (self.rate_factor / 2.0).max(MIN_RATE_FACTOR);
+    }
+
+    /// Goes back towards the configured rate after a successful edit
+    pub fn speed_up(&mut self) {
+        self.rate_factor = (self.rate_factor * 1.25).min(1.0);
+    }
+
+    pub fn edits_per_second(&self) -> f64 {
+        f64::from(self.config.edits_per_minute) / 60.0 * self.rate_factor
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn throttle_rate_and_burst() {
+        let mut throttle = Throttle::new(ThrottleConfig {
+            edits_per_minute: 60,
+            burst: 2,
+            ..Default::default()
+        });
+        let start = Instant::now();
+        assert_eq!(throttle.reserve(start), Duration::from_secs(0));
+        assert_eq!(throttle.reserve(start), Duration::from_secs(0));
+        assert_eq!(throttle.reserve(start), Duration::from_secs(1));
+        // Waited as told; the next one has to wait a full interval again
+        assert_eq!(
+            throttle.reserve(start + Duration::from_secs(1)),
+            Duration::from_secs(1)
+        );
+
+        throttle.slow_down();
+        assert_eq!(throttle.edits_per_second(), 0.5);
+        (0..4).for_each(|_| throttle.speed_up());
+        assert_eq!(throttle.edits_per_second(), 1.0);
+
+        let delayed = Throttle::new(ThrottleConfig {
+            edit_delay_ms: Some(500),
+            ..Default::default()
+        });
+        assert_eq!(delayed.edits_per_second(), 2.0);
+
+        let mut unlimited = Throttle::unlimited();
+        assert_eq!(unlimited.reserve(start), Duration::from_secs(0));
+        assert_eq!(unlimited.reserve(start), Duration::from_secs(0));
+    }
+
+    #[test]
+    fn quiet_hours() {
+        let night = ThrottleConfig {
+            quiet_from: Some(22),
+            quiet_until: Some(6),
+            ..Default::default()
+        };
+        assert!(night.is_quiet_hour(23));
+        assert!(night.is_quiet_hour(0));
+        assert!(!night.is_quiet_hour(6));
+        assert!(!night.is_quiet_hour(12));
+        let afternoon = ThrottleConfig {
+            quiet_from: Some(13),
+            quiet_until: Some(15),
+            ..Default::default()
+        };
+        assert!(afternoon.is_quiet_hour(14));
+        assert!(!afternoon.is_quiet_hour(15));
+        assert!(!ThrottleConfig::default().is_quiet_hour(3));
+    }
+}